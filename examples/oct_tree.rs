@@ -131,10 +131,10 @@ fn check_oct_tree(
                     }
 
                     for new_child in children {
-                        let child_node = &oct_tree.nodes[new_child];
+                        let child_node = &oct_tree.nodes[new_child.node_key];
                         let child_id =
                             spawn_oct_box(&mut commands, &mut meshes, &mut materials, child_node);
-                        spawned_nodes.0.insert(new_child, child_id);
+                        spawned_nodes.0.insert(new_child.node_key, child_id);
                     }
                 }
                 TreeEvent::Shrunk { retained, removed } => {
@@ -142,9 +142,9 @@ fn check_oct_tree(
                     shrink_events.1 += removed.len();
 
                     for removed_node in removed {
-                        if let Some(node_entity) = spawned_nodes.0.get(&removed_node) {
+                        if let Some(node_entity) = spawned_nodes.0.get(&removed_node.node_key) {
                             commands.entity(*node_entity).despawn();
-                            spawned_nodes.0.remove(&removed_node);
+                            spawned_nodes.0.remove(&removed_node.node_key);
                         }
                     }
 
@@ -153,7 +153,7 @@ fn check_oct_tree(
                         spawn_oct_box(&mut commands, &mut meshes, &mut materials, child_node)
                     });
                 }
-                TreeEvent::NeighborSizesChanged(_) => {
+                TreeEvent::NeighborSizesChanged { .. } => {
                     neighbor_changed_events += 1;
                 }
             }
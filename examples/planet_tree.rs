@@ -173,10 +173,10 @@ fn check_planet_tree(
                     }
 
                     for new_child in children {
-                        let child_node = &planet_tree.nodes[new_child];
+                        let child_node = &planet_tree.nodes[new_child.node_key];
                         let child_id =
                             spawn_plane(&mut commands, &mut meshes, &plane_material, child_node);
-                        spawned_nodes.0.insert(new_child, child_id);
+                        spawned_nodes.0.insert(new_child.node_key, child_id);
                     }
                 }
                 TreeEvent::Shrunk { retained, removed } => {
@@ -184,9 +184,9 @@ fn check_planet_tree(
                     shrink_events.1 += removed.len();
 
                     for removed_node in removed {
-                        if let Some(node_entity) = spawned_nodes.0.get(&removed_node) {
+                        if let Some(node_entity) = spawned_nodes.0.get(&removed_node.node_key) {
                             commands.entity(*node_entity).despawn();
-                            spawned_nodes.0.remove(&removed_node);
+                            spawned_nodes.0.remove(&removed_node.node_key);
                         }
                     }
 
@@ -195,7 +195,7 @@ fn check_planet_tree(
                         spawn_plane(&mut commands, &mut meshes, &plane_material, child_node)
                     });
                 }
-                TreeEvent::NeighborSizesChanged(_) => {
+                TreeEvent::NeighborSizesChanged { .. } => {
                     neighbor_changed_events += 1;
                 }
             }
@@ -118,7 +118,7 @@ fn check_quad_tree(
                     }
 
                     for new_child in children {
-                        let child_node = &quad_tree.nodes[new_child];
+                        let child_node = &quad_tree.nodes[new_child.node_key];
                         let child_id = commands
                             .spawn_bundle(PbrBundle {
                                 mesh: meshes.add(Mesh::from(shape::Plane {
@@ -133,14 +133,14 @@ fn check_quad_tree(
                                 ..Default::default()
                             })
                             .id();
-                        spawned_nodes.0.insert(new_child, child_id);
+                        spawned_nodes.0.insert(new_child.node_key, child_id);
                     }
                 }
                 TreeEvent::Shrunk { retained, removed } => {
                     for removed_node in removed {
-                        if let Some(node_entity) = spawned_nodes.0.get(&removed_node) {
+                        if let Some(node_entity) = spawned_nodes.0.get(&removed_node.node_key) {
                             commands.entity(*node_entity).despawn();
-                            spawned_nodes.0.remove(&removed_node);
+                            spawned_nodes.0.remove(&removed_node.node_key);
                         }
                     }
 
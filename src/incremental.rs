@@ -0,0 +1,195 @@
+use std::{
+    collections::BinaryHeap,
+    time::{Duration, Instant},
+};
+
+use crate::{node_traits::*, tree_traits::*};
+
+/// Limits how much work one `IncrementalRefiner::step` may do. Unset limits are ignored.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct RefineBudget {
+    pub max_operations: Option<usize>,
+    pub max_duration: Option<Duration>,
+}
+
+impl RefineBudget {
+    /// At most `max_operations` splits and merges per step.
+    pub fn operations(max_operations: usize) -> Self {
+        Self {
+            max_operations: Some(max_operations),
+            max_duration: None,
+        }
+    }
+
+    /// Stop starting new work after `max_duration`.
+    pub fn duration(max_duration: Duration) -> Self {
+        Self {
+            max_operations: None,
+            max_duration: Some(max_duration),
+        }
+    }
+
+    fn exhausted(&self, operations: usize, start: Instant) -> bool {
+        self.max_operations
+            .is_some_and(|max_operations| operations >= max_operations)
+            || self
+                .max_duration
+                .is_some_and(|max_duration| start.elapsed() >= max_duration)
+    }
+}
+
+/// Spreads the work of `insert_and_update_neighbors` over several calls.
+///
+/// Pending nodes are kept between steps and handled highest priority first. Once all pending nodes are handled the pass
+/// is complete, and the next step starts a new pass from the roots.
+#[derive(Debug, Default)]
+pub struct IncrementalRefiner {
+    pending: BinaryHeap<NodePriority>,
+}
+
+impl IncrementalRefiner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// True if the current pass is complete.
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Drops the pending nodes, so the next step starts a new pass from the roots.
+    pub fn restart(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Refines the tree like `insert_and_update_neighbors` until the budget is used up or the pass is complete,
+    /// and returns the events of this step.
+    ///
+    /// `priority` decides which pending node is handled next, highest first. Using how far a node is past its split
+    /// threshold, e.g. node size divided by distance to the viewer, makes the tree converge coarse to fine around the viewer.
+    pub fn step<Tree, const D: usize>(
+        &mut self,
+        tree: &mut Tree,
+        f: impl Fn(&Tree::NodeType) -> bool,
+        priority: impl Fn(&Tree::NodeType) -> f32,
+        budget: RefineBudget,
+    ) -> Vec<TreeEvent<D, Tree::Face>>
+    where
+        Tree: TreeNeighbourBehaviour<D>,
+        Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+    {
+        if self.pending.is_empty() {
+            for node_key in tree.root_items() {
+                let node_priority = priority(tree.get_node_unchecked(node_key));
+                self.pending.push(NodePriority::new(node_priority, node_key));
+            }
+        }
+
+        let start = Instant::now();
+        let mut operations = 0;
+        let mut events = vec![];
+        while !budget.exhausted(operations, start) {
+            let node_key = match self.pending.pop() {
+                Some(NodePriority { node_key, .. }) => node_key,
+                None => break,
+            };
+            // Nodes removed by an earlier merge are skipped
+            let node = match tree.get_node(node_key) {
+                Some(node) => node,
+                None => continue,
+            };
+
+            if f(node) {
+                let children = if let Some(children) = node.children() {
+                    children.to_vec()
+                } else if tree.can_split(node_key) {
+                    let parent_pos = node.pos();
+                    let new_children = tree.create_children(node_key);
+                    tree.grow_event(&mut events, parent_pos, node_key, &new_children);
+                    operations += 1;
+                    new_children
+                } else {
+                    vec![]
+                };
+                for child_key in children {
+                    let child_priority = priority(tree.get_node_unchecked(child_key));
+                    self.pending.push(NodePriority::new(child_priority, child_key));
+                }
+            } else if let Some(children) = node.children() {
+                if tree.is_merge_protected(node_key) {
+                    for child_key in children.iter().copied() {
+                        let child_priority = priority(tree.get_node_unchecked(child_key));
+                        self.pending.push(NodePriority::new(child_priority, child_key));
+                    }
+                } else {
+                    tree.shrink_event(&mut events, node_key);
+                    operations += 1;
+                }
+            }
+        }
+
+        tree.update_neighbors_from_events(&mut events);
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        observer::TreeObserver,
+        quad_tree::{QuadTree, QuadTreeNode},
+        test_utils::leaf_sides,
+        NodeKey,
+    };
+    use std::sync::{Arc, Mutex};
+
+    // Counts splits and merges, which events may fold together
+    struct OperationCounter(Arc<Mutex<usize>>);
+
+    impl TreeObserver<2> for OperationCounter {
+        fn on_split(&mut self, _parent: NodeKey, _children: &[NodeSnapshot<2>]) {
+            *self.0.lock().unwrap() += 1;
+        }
+
+        fn on_merge(&mut self, _parent: NodeKey, _removed: &[NodeSnapshot<2>]) {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+
+    fn near(point: [f32; 2]) -> impl Fn(&QuadTreeNode) -> bool {
+        move |node| node.size > 2.0 && node.overlap_sphere(point, 6.0) != Overlap::Disjoint
+    }
+
+    #[test]
+    fn steps_stay_within_budget_and_converge_to_full_refine() {
+        let operations = Arc::new(Mutex::new(0));
+        let mut tree = QuadTree::new(1.0, 64.0, [0.0, 0.0]).with_observer(OperationCounter(operations.clone()));
+        let mut full = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        let mut refiner = IncrementalRefiner::new();
+        let priority = |node: &QuadTreeNode| node.size;
+
+        for point in [[10.0, 10.0], [-20.0, 4.0]] {
+            full.insert_and_update_neighbors(near(point));
+
+            let mut steps = 0;
+            loop {
+                *operations.lock().unwrap() = 0;
+                refiner.step(&mut tree, near(point), priority, RefineBudget::operations(3));
+                assert!(*operations.lock().unwrap() <= 3);
+                steps += 1;
+                if refiner.is_idle() {
+                    break;
+                }
+            }
+            // The work left over from each step was picked up by the next one
+            assert!(steps > 1);
+            assert_eq!(leaf_sides(&tree), leaf_sides(&full));
+
+            // A converged tree has nothing left to do
+            let events = refiner.step(&mut tree, near(point), priority, RefineBudget::operations(3));
+            assert!(events.is_empty());
+            assert!(refiner.is_idle());
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use crate::{
+    node_traits::*, ntree::NTree, planet_tree_impl::PlanetTree, planet_tree_node::PlanetTreeNode, tree_traits::*,
+    VolumeKey,
+};
+use slotmap::SlotMap;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InterestShape<const V: usize> {
+    Sphere { center: [f32; V], radius: f32 },
+    Aabb { min: [f32; V], max: [f32; V] },
+}
+
+impl<const V: usize> InterestShape<V> {
+    pub fn bounds(&self) -> ([f32; V], [f32; V]) {
+        match *self {
+            InterestShape::Sphere { center, radius } => (center.map(|v| v - radius), center.map(|v| v + radius)),
+            InterestShape::Aabb { min, max } => (min, max),
+        }
+    }
+
+    /// Touching counts as intersecting.
+    pub fn intersects_aabb(&self, min: [f32; V], max: [f32; V]) -> bool {
+        match *self {
+            InterestShape::Sphere { center, radius } => aabb_distance_squared(min, max, center) <= radius * radius,
+            InterestShape::Aabb { min: shape_min, max: shape_max } => {
+                aabb_intersects_aabb(shape_min, shape_max, min, max)
+            }
+        }
+    }
+}
+
+/// A region that should be refined until the nodes overlapping it are no larger than `target_size`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct InterestVolume<const V: usize> {
+    pub shape: InterestShape<V>,
+    pub target_size: f32,
+}
+
+impl<const V: usize> InterestVolume<V> {
+    pub fn sphere(center: [f32; V], radius: f32, target_size: f32) -> Self {
+        Self {
+            shape: InterestShape::Sphere { center, radius },
+            target_size,
+        }
+    }
+
+    pub fn aabb(min: [f32; V], max: [f32; V], target_size: f32) -> Self {
+        Self {
+            shape: InterestShape::Aabb { min, max },
+            target_size,
+        }
+    }
+}
+
+/// Gives node bounds in the space interest volumes are given in.
+/// Tree space for `QuadTree` and `OctTree`, world space for `PlanetTree`.
+pub trait InterestSpace<const V: usize>: NodeStorage {
+    fn interest_bounds(node: &Self::NodeType) -> ([f32; V], [f32; V]);
+}
+
+impl<T, const D: usize> InterestSpace<D> for NTree<T, D>
+where
+    T: ChildBehaviour<D> + NeighborBehaviour<D> + Boundary<D> + std::fmt::Debug,
+{
+    fn interest_bounds(node: &T) -> ([f32; D], [f32; D]) {
+        node.bounds()
+    }
+}
+
+impl InterestSpace<3> for PlanetTree {
+    fn interest_bounds(node: &PlanetTreeNode) -> ([f32; 3], [f32; 3]) {
+        node.world_bounds()
+    }
+}
+
+/// Registered interest volumes. The tree is kept refined to the union of all volumes: a node splits if it is
+/// larger than the target size of any volume overlapping it, and merges otherwise.
+///
+/// Adding, updating and removing a volume only revisits the nodes overlapping the volume's old and new bounds,
+/// so the tree should not be refined by other means in between. Call `refine` for a full pass.
+#[derive(Debug, Default)]
+pub struct InterestVolumes<const V: usize> {
+    volumes: SlotMap<VolumeKey, InterestVolume<V>>,
+}
+
+impl<const V: usize> InterestVolumes<V> {
+    pub fn new() -> Self {
+        Self {
+            volumes: SlotMap::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.volumes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.volumes.is_empty()
+    }
+
+    pub fn get(&self, volume_key: VolumeKey) -> Option<&InterestVolume<V>> {
+        self.volumes.get(volume_key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (VolumeKey, &InterestVolume<V>)> {
+        self.volumes.iter()
+    }
+
+    /// True if any volume wants the node split.
+    pub fn wants_split<Tree, const D: usize>(&self, node: &Tree::NodeType) -> bool
+    where
+        Tree: InterestSpace<V>,
+        Tree::NodeType: Boundary<D>,
+    {
+        let (min, max) = Tree::interest_bounds(node);
+        self.volumes
+            .values()
+            .any(|volume| node.size() > volume.target_size && volume.shape.intersects_aabb(min, max))
+    }
+
+    pub fn add<Tree, const D: usize>(
+        &mut self,
+        tree: &mut Tree,
+        volume: InterestVolume<V>,
+    ) -> (VolumeKey, Vec<TreeEvent<D, Tree::Face>>)
+    where
+        Tree: TreeNeighbourBehaviour<D> + InterestSpace<V>,
+        Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+    {
+        let volume_key = self.volumes.insert(volume);
+        let (min, max) = volume.shape.bounds();
+        (volume_key, self.refine_region(tree, min, max))
+    }
+
+    /// Replaces the shape and target size of a volume. Returns no events if the volume does not exist.
+    pub fn update<Tree, const D: usize>(
+        &mut self,
+        tree: &mut Tree,
+        volume_key: VolumeKey,
+        volume: InterestVolume<V>,
+    ) -> Vec<TreeEvent<D, Tree::Face>>
+    where
+        Tree: TreeNeighbourBehaviour<D> + InterestSpace<V>,
+        Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+    {
+        let Some(old_volume) = self.volumes.get_mut(volume_key) else {
+            return vec![];
+        };
+        let (old_min, old_max) = std::mem::replace(old_volume, volume).shape.bounds();
+        let (new_min, new_max) = volume.shape.bounds();
+        let min = std::array::from_fn(|i| old_min[i].min(new_min[i]));
+        let max = std::array::from_fn(|i| old_max[i].max(new_max[i]));
+        self.refine_region(tree, min, max)
+    }
+
+    /// Moves a volume, keeping its target size.
+    pub fn set_shape<Tree, const D: usize>(
+        &mut self,
+        tree: &mut Tree,
+        volume_key: VolumeKey,
+        shape: InterestShape<V>,
+    ) -> Vec<TreeEvent<D, Tree::Face>>
+    where
+        Tree: TreeNeighbourBehaviour<D> + InterestSpace<V>,
+        Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+    {
+        match self.volumes.get(volume_key) {
+            Some(volume) => {
+                let volume = InterestVolume {
+                    shape,
+                    target_size: volume.target_size,
+                };
+                self.update(tree, volume_key, volume)
+            }
+            None => vec![],
+        }
+    }
+
+    pub fn remove<Tree, const D: usize>(
+        &mut self,
+        tree: &mut Tree,
+        volume_key: VolumeKey,
+    ) -> (Option<InterestVolume<V>>, Vec<TreeEvent<D, Tree::Face>>)
+    where
+        Tree: TreeNeighbourBehaviour<D> + InterestSpace<V>,
+        Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+    {
+        match self.volumes.remove(volume_key) {
+            Some(volume) => {
+                let (min, max) = volume.shape.bounds();
+                (Some(volume), self.refine_region(tree, min, max))
+            }
+            None => (None, vec![]),
+        }
+    }
+
+    /// Refines the whole tree to the volumes.
+    pub fn refine<Tree, const D: usize>(&self, tree: &mut Tree) -> Vec<TreeEvent<D, Tree::Face>>
+    where
+        Tree: TreeNeighbourBehaviour<D> + InterestSpace<V>,
+        Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+    {
+        tree.insert_and_update_neighbors(|node| self.wants_split::<Tree, D>(node))
+    }
+
+    fn refine_region<Tree, const D: usize>(&self, tree: &mut Tree, min: [f32; V], max: [f32; V]) -> Vec<TreeEvent<D, Tree::Face>>
+    where
+        Tree: TreeNeighbourBehaviour<D> + InterestSpace<V>,
+        Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+    {
+        tree.insert_in_region_and_update_neighbors(
+            |node| {
+                let (node_min, node_max) = Tree::interest_bounds(node);
+                aabb_intersects_aabb(node_min, node_max, min, max)
+            },
+            |node| self.wants_split::<Tree, D>(node),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        quad_tree::QuadTree,
+        test_utils::{leaf_sides, leaf_sides_by},
+    };
+
+    // Applies the same changes to two trees, one refined by the volumes and the other by full passes
+    struct Comparison<Tree, const V: usize> {
+        tree: Tree,
+        full: Tree,
+        volumes: InterestVolumes<V>,
+    }
+
+    impl<Tree, const V: usize> Comparison<Tree, V> {
+        fn check<const D: usize>(&mut self, sides: impl Fn(&Tree) -> Vec<String>)
+        where
+            Tree: TreeNeighbourBehaviour<D> + InterestSpace<V>,
+            Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+        {
+            self.volumes.refine(&mut self.full);
+            assert_eq!(sides(&self.tree), sides(&self.full));
+        }
+    }
+
+    #[test]
+    fn quad_tree_updates_match_full_refine() {
+        let mut c = Comparison {
+            tree: QuadTree::new(1.0, 64.0, [0.0, 0.0]),
+            full: QuadTree::new(1.0, 64.0, [0.0, 0.0]),
+            volumes: InterestVolumes::new(),
+        };
+
+        let (sphere, _) = c.volumes.add(&mut c.tree, InterestVolume::sphere([10.0, 10.0], 6.0, 2.0));
+        c.check(leaf_sides);
+        let (aabb, _) = c.volumes.add(&mut c.tree, InterestVolume::aabb([-30.0, -8.0], [-20.0, 4.0], 4.0));
+        c.check(leaf_sides);
+        for center in [[12.0, 9.0], [0.0, -20.0], [-24.0, 0.0]] {
+            c.volumes.set_shape(&mut c.tree, sphere, InterestShape::Sphere { center, radius: 6.0 });
+            c.check(leaf_sides);
+        }
+        c.volumes.remove(&mut c.tree, aabb);
+        c.check(leaf_sides);
+        c.volumes.remove(&mut c.tree, sphere);
+        c.check(leaf_sides);
+        assert_eq!(c.tree.iter_leaf_nodes().count(), 1);
+    }
+
+    #[test]
+    fn planet_tree_updates_match_full_refine() {
+        let world_sides = |tree: &PlanetTree| leaf_sides_by(tree, |node| format!("{:?}", node.world_bounds()));
+        let mut c = Comparison {
+            tree: PlanetTree::new(1.0, 64.0, [0.0, 0.0, 0.0]),
+            full: PlanetTree::new(1.0, 64.0, [0.0, 0.0, 0.0]),
+            volumes: InterestVolumes::new(),
+        };
+
+        // Volumes on the cube edges and corners refine several faces
+        let (sphere, _) = c.volumes.add(&mut c.tree, InterestVolume::sphere([32.0, 32.0, 0.0], 8.0, 2.0));
+        c.check(world_sides);
+        let aabb_volume = InterestVolume::aabb([-40.0, -10.0, -10.0], [-30.0, 10.0, 0.0], 4.0);
+        let (aabb, _) = c.volumes.add(&mut c.tree, aabb_volume);
+        c.check(world_sides);
+        for center in [[32.0, 32.0, 32.0], [0.0, 0.0, -32.0]] {
+            c.volumes.set_shape(&mut c.tree, sphere, InterestShape::Sphere { center, radius: 8.0 });
+            c.check(world_sides);
+        }
+        c.volumes.remove(&mut c.tree, aabb);
+        c.check(world_sides);
+        c.volumes.remove(&mut c.tree, sphere);
+        c.check(world_sides);
+        assert_eq!(c.tree.iter_leaf_nodes().count(), 6);
+    }
+}
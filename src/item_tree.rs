@@ -0,0 +1,585 @@
+use crate::{node_traits::*, ntree::NTree, tree_traits::*, ItemKey, NodeKey};
+use slotmap::{SecondaryMap, SlotMap};
+
+#[derive(Debug)]
+pub struct ItemEntry<I, const D: usize> {
+    pub item: I,
+    pub min: [f32; D],
+    pub max: [f32; D],
+    node: NodeKey,
+}
+
+impl<I, const D: usize> ItemEntry<I, D> {
+    /// The node currently holding the item.
+    pub fn node(&self) -> NodeKey {
+        self.node
+    }
+}
+
+/// An `NTree` that stores items with a position or bounding box.
+/// Each item is kept in the deepest node that fully contains it, so points always end up in leaves.
+/// Leaves split when they hold more than `bucket_capacity` items, down to the tree's resolution limits,
+/// and a node whose subtree holds no more than `merge_threshold` items collapses again.
+///
+/// With a `looseness` above 1.0 the tree becomes a loose tree: every node accepts items that fit inside
+/// its bounds scaled by the looseness, which keeps moving items from bouncing between levels.
+pub struct ItemTree<T, I, const D: usize>
+where
+    T: ChildBehaviour<D> + NeighborBehaviour<D> + Boundary<D>,
+{
+    tree: NTree<T, D>,
+    items: SlotMap<ItemKey, ItemEntry<I, D>>,
+    node_items: SecondaryMap<NodeKey, Vec<ItemKey>>,
+    pub bucket_capacity: usize,
+    pub merge_threshold: usize,
+    looseness: f32,
+    events: Vec<TreeEvent<D>>,
+}
+
+impl<T, I, const D: usize> ItemTree<T, I, D>
+where
+    T: ChildBehaviour<D> + NeighborBehaviour<D> + Boundary<D> + std::fmt::Debug,
+{
+    pub fn new(min_size: f32, size: f32, pos: [f32; D], bucket_capacity: usize) -> Self {
+        Self {
+            tree: NTree::new(min_size, size, pos),
+            items: SlotMap::default(),
+            node_items: SecondaryMap::default(),
+            bucket_capacity,
+            merge_threshold: bucket_capacity / 2,
+            looseness: 1.0,
+            events: vec![],
+        }
+    }
+
+    /// Must be below `bucket_capacity`, so a node that just split does not collapse again.
+    pub fn with_merge_threshold(mut self, merge_threshold: usize) -> Self {
+        assert!(merge_threshold < self.bucket_capacity, "merge_threshold must be below bucket_capacity");
+        self.merge_threshold = merge_threshold;
+        self
+    }
+
+    /// Scales the bounds used for item placement. Must be set before any items are inserted.
+    pub fn with_looseness(mut self, looseness: f32) -> Self {
+        assert!(looseness >= 1.0, "looseness must be at least 1.0");
+        assert!(self.items.is_empty(), "looseness must be set before any items are inserted");
+        self.looseness = looseness;
+        self
+    }
+
+    pub fn looseness(&self) -> f32 {
+        self.looseness
+    }
+
+    /// Node bounds scaled by the looseness factor.
+    pub fn loose_bounds(&self, node_key: NodeKey) -> ([f32; D], [f32; D]) {
+        let node = &self.tree.nodes[node_key];
+        let half_size = node.size() * self.looseness / 2.0;
+        let pos = node.pos();
+
+        let mut min = pos;
+        let mut max = pos;
+        for i in 0..D {
+            min[i] -= half_size;
+            max[i] += half_size;
+        }
+        (min, max)
+    }
+
+    pub fn tree(&self) -> &NTree<T, D> {
+        &self.tree
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn get(&self, item_key: ItemKey) -> Option<&I> {
+        self.items.get(item_key).map(|entry| &entry.item)
+    }
+
+    pub fn get_mut(&mut self, item_key: ItemKey) -> Option<&mut I> {
+        self.items.get_mut(item_key).map(|entry| &mut entry.item)
+    }
+
+    pub fn entry(&self, item_key: ItemKey) -> Option<&ItemEntry<I, D>> {
+        self.items.get(item_key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ItemKey, &ItemEntry<I, D>)> {
+        self.items.iter()
+    }
+
+    /// Items stored directly in a node, not including its children.
+    pub fn node_items(&self, node_key: NodeKey) -> &[ItemKey] {
+        self.node_items
+            .get(node_key)
+            .map(|items| items.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Returns the tree events produced by inserts and removals since the last call, with neighbor sizes already updated.
+    pub fn take_events(&mut self) -> Vec<TreeEvent<D>> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn insert_point(&mut self, item: I, pos: [f32; D]) -> ItemKey {
+        self.insert(item, pos, pos)
+    }
+
+    pub fn insert(&mut self, item: I, min: [f32; D], max: [f32; D]) -> ItemKey {
+        let node_key = self.find_node(self.tree.root(), min, max);
+        let item_key = self.items.insert(ItemEntry {
+            item,
+            min,
+            max,
+            node: node_key,
+        });
+        self.add_to_node(node_key, item_key);
+
+        let mut events = vec![];
+        self.split_if_full(node_key, &mut events);
+        self.finish_events(events);
+        item_key
+    }
+
+    pub fn remove(&mut self, item_key: ItemKey) -> Option<I> {
+        let entry = self.items.remove(item_key)?;
+        self.remove_from_node(entry.node, item_key);
+
+        let mut events = vec![];
+        self.merge_if_sparse(entry.node, &mut events);
+        self.finish_events(events);
+        Some(entry.item)
+    }
+
+    /// Moves an item to new bounds. The item only climbs as far up as needed to fit and then descends as deep as it can,
+    /// instead of being re-inserted from the root. Returns false if the item does not exist.
+    pub fn update(&mut self, item_key: ItemKey, min: [f32; D], max: [f32; D]) -> bool {
+        let old_node_key = match self.items.get_mut(item_key) {
+            Some(entry) => {
+                entry.min = min;
+                entry.max = max;
+                entry.node
+            }
+            None => return false,
+        };
+
+        let mut node_key = old_node_key;
+        while !self.fits(node_key, min, max) {
+            match self.tree.nodes[node_key].get_parent() {
+                Some(parent_key) => node_key = parent_key,
+                None => break,
+            }
+        }
+        let new_node_key = self.find_node(node_key, min, max);
+        if new_node_key == old_node_key {
+            return true;
+        }
+
+        self.remove_from_node(old_node_key, item_key);
+        self.add_to_node(new_node_key, item_key);
+
+        let mut events = vec![];
+        self.split_if_full(new_node_key, &mut events);
+        self.finish_events(events);
+
+        if self.tree.nodes.contains_key(old_node_key) {
+            let mut events = vec![];
+            self.merge_if_sparse(old_node_key, &mut events);
+            self.finish_events(events);
+        }
+        true
+    }
+
+    pub fn update_point(&mut self, item_key: ItemKey, pos: [f32; D]) -> bool {
+        self.update(item_key, pos, pos)
+    }
+
+    /// Returns all items whose bounds overlap the box.
+    pub fn query_aabb(&self, min: [f32; D], max: [f32; D]) -> Vec<ItemKey> {
+        let mut found = vec![];
+        let mut pending_node_keys = vec![self.tree.root()];
+        while let Some(node_key) = pending_node_keys.pop() {
+            for item_key in self.node_items(node_key) {
+                let entry = &self.items[*item_key];
+                if aabb_intersects_aabb(entry.min, entry.max, min, max) {
+                    found.push(*item_key);
+                }
+            }
+            if let Some(children) = self.tree.nodes[node_key].children() {
+                for child_key in children {
+                    let (child_min, child_max) = self.loose_bounds(*child_key);
+                    if aabb_intersects_aabb(child_min, child_max, min, max) {
+                        pending_node_keys.push(*child_key);
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    /// All pairs of items whose bounds overlap or touch, the broadphase candidates for collision checks.
+    pub fn overlapping_pairs(&self) -> Vec<(ItemKey, ItemKey)> {
+        let mut pairs = vec![];
+        self.for_each_pair_within(0.0, |a, b| pairs.push((a, b)));
+        pairs
+    }
+
+    /// All pairs of items whose bounds are at most `radius` apart. For point items this is the center distance.
+    pub fn pairs_within(&self, radius: f32) -> Vec<(ItemKey, ItemKey)> {
+        let mut pairs = vec![];
+        self.for_each_pair_within(radius, |a, b| pairs.push((a, b)));
+        pairs
+    }
+
+    /// Fixed-radius neighbor list for every item, e.g. for particle simulations.
+    pub fn neighbor_lists(&self, radius: f32) -> SecondaryMap<ItemKey, Vec<ItemKey>> {
+        let mut neighbors = SecondaryMap::with_capacity(self.items.len());
+        for item_key in self.items.keys() {
+            neighbors.insert(item_key, vec![]);
+        }
+        self.for_each_pair_within(radius, |a, b| {
+            neighbors[a].push(b);
+            neighbors[b].push(a);
+        });
+        neighbors
+    }
+
+    /// Calls `f` once for every pair of items whose bounds are at most `radius` apart.
+    /// Items in a node are only tested against each other and against the subtrees of the node that are in reach,
+    /// so items in large nodes never get tested against far away parts of the tree.
+    pub fn for_each_pair_within(&self, radius: f32, mut f: impl FnMut(ItemKey, ItemKey)) {
+        let radius_squared = radius * radius;
+        let mut pending_node_keys = vec![self.tree.root()];
+        while let Some(node_key) = pending_node_keys.pop() {
+            let node_items = self.node_items(node_key);
+            for (i, item_key) in node_items.iter().enumerate() {
+                for other_key in &node_items[i + 1..] {
+                    if self.items_within(*item_key, *other_key, radius_squared) {
+                        f(*item_key, *other_key);
+                    }
+                }
+            }
+
+            if let Some(children) = self.tree.nodes[node_key].children() {
+                for (i, child_key) in children.iter().enumerate() {
+                    for item_key in node_items {
+                        self.item_pairs_in_subtree(*item_key, *child_key, radius_squared, &mut f);
+                    }
+                    for other_child_key in &children[i + 1..] {
+                        self.subtree_pairs(*child_key, *other_child_key, radius_squared, &mut f);
+                    }
+                }
+                pending_node_keys.extend(children.iter());
+            }
+        }
+    }
+
+    // Tests one item against every item in a subtree in reach of it.
+    fn item_pairs_in_subtree(
+        &self,
+        item_key: ItemKey,
+        node_key: NodeKey,
+        radius_squared: f32,
+        f: &mut impl FnMut(ItemKey, ItemKey),
+    ) {
+        let entry = &self.items[item_key];
+        let mut pending_node_keys = vec![node_key];
+        while let Some(node_key) = pending_node_keys.pop() {
+            let (node_min, node_max) = self.loose_bounds(node_key);
+            if aabb_gap_distance_squared(entry.min, entry.max, node_min, node_max) > radius_squared {
+                continue;
+            }
+            for other_key in self.node_items(node_key) {
+                if self.items_within(item_key, *other_key, radius_squared) {
+                    f(item_key, *other_key);
+                }
+            }
+            if let Some(children) = self.tree.nodes[node_key].children() {
+                pending_node_keys.extend(children.iter());
+            }
+        }
+    }
+
+    // Tests every item in one subtree against every item in another, disjoint, subtree.
+    fn subtree_pairs(
+        &self,
+        node_key: NodeKey,
+        other_node_key: NodeKey,
+        radius_squared: f32,
+        f: &mut impl FnMut(ItemKey, ItemKey),
+    ) {
+        let (other_min, other_max) = self.loose_bounds(other_node_key);
+        let mut pending_node_keys = vec![node_key];
+        while let Some(node_key) = pending_node_keys.pop() {
+            let (node_min, node_max) = self.loose_bounds(node_key);
+            if aabb_gap_distance_squared(node_min, node_max, other_min, other_max) > radius_squared {
+                continue;
+            }
+            for item_key in self.node_items(node_key) {
+                self.item_pairs_in_subtree(*item_key, other_node_key, radius_squared, f);
+            }
+            if let Some(children) = self.tree.nodes[node_key].children() {
+                pending_node_keys.extend(children.iter());
+            }
+        }
+    }
+
+    fn items_within(&self, item_key: ItemKey, other_key: ItemKey, radius_squared: f32) -> bool {
+        let (a, b) = (&self.items[item_key], &self.items[other_key]);
+        aabb_gap_distance_squared(a.min, a.max, b.min, b.max) <= radius_squared
+    }
+
+    /// Number of items stored in a node and all of its descendants.
+    pub fn subtree_item_count(&self, node_key: NodeKey) -> usize {
+        let mut count = 0;
+        let mut pending_node_keys = vec![node_key];
+        while let Some(node_key) = pending_node_keys.pop() {
+            count += self.node_items(node_key).len();
+            if let Some(children) = self.tree.nodes[node_key].children() {
+                pending_node_keys.extend(children.iter());
+            }
+        }
+        count
+    }
+
+    // Descends from `node_key` to the deepest existing node that contains the box.
+    // Items outside the root are kept in the root.
+    fn find_node(&self, mut node_key: NodeKey, min: [f32; D], max: [f32; D]) -> NodeKey {
+        while let Some(child_key) = self.child_containing(node_key, min, max) {
+            node_key = child_key;
+        }
+        node_key
+    }
+
+    // Picks the child holding the center of the box and checks that the box fits in its loose bounds.
+    fn child_containing(&self, node_key: NodeKey, min: [f32; D], max: [f32; D]) -> Option<NodeKey> {
+        let node = &self.tree.nodes[node_key];
+        let pos = node.pos();
+        let mut direction = [0; D];
+        for i in 0..D {
+            direction[i] = if (min[i] + max[i]) / 2.0 < pos[i] { -1 } else { 1 };
+        }
+        node.get_child(direction)
+            .filter(|child_key| self.fits(*child_key, min, max))
+    }
+
+    fn fits(&self, node_key: NodeKey, min: [f32; D], max: [f32; D]) -> bool {
+        let (node_min, node_max) = self.loose_bounds(node_key);
+        aabb_contains_aabb(node_min, node_max, min, max)
+    }
+
+    fn add_to_node(&mut self, node_key: NodeKey, item_key: ItemKey) {
+        self.items[item_key].node = node_key;
+        match self.node_items.get_mut(node_key) {
+            Some(items) => items.push(item_key),
+            None => {
+                self.node_items.insert(node_key, vec![item_key]);
+            }
+        }
+    }
+
+    fn remove_from_node(&mut self, node_key: NodeKey, item_key: ItemKey) {
+        if let Some(items) = self.node_items.get_mut(node_key) {
+            if let Some(index) = items.iter().position(|k| *k == item_key) {
+                items.swap_remove(index);
+            }
+        }
+    }
+
+    fn split_if_full(&mut self, node_key: NodeKey, events: &mut Vec<TreeEvent<D>>) {
+        let mut pending_node_keys = vec![node_key];
+        while let Some(node_key) = pending_node_keys.pop() {
+            let node = &self.tree.nodes[node_key];
+            if node.has_children()
+                || self.node_items(node_key).len() <= self.bucket_capacity
+                || !self.tree.can_split(node_key)
+            {
+                continue;
+            }
+
+            let parent_pos = node.pos();
+            let new_children = self.tree.create_children(node_key);
+            self.tree.grow_event(events, parent_pos, node_key, &new_children);
+
+            let items = self.node_items.remove(node_key).unwrap_or_default();
+            for item_key in items {
+                let (min, max) = (self.items[item_key].min, self.items[item_key].max);
+                let target_key = self.child_containing(node_key, min, max).unwrap_or(node_key);
+                self.add_to_node(target_key, item_key);
+            }
+            pending_node_keys.extend(new_children);
+        }
+    }
+
+    fn merge_if_sparse(&mut self, node_key: NodeKey, events: &mut Vec<TreeEvent<D>>) {
+        let mut candidate = if self.tree.nodes[node_key].has_children() {
+            Some(node_key)
+        } else {
+            self.tree.nodes[node_key].get_parent()
+        };
+
+        // Find the topmost node that can collapse, treating already collapsible children as leaves.
+        let mut merged = None;
+        while let Some(parent_key) = candidate {
+            let children = self.tree.nodes[parent_key].children().unwrap_or(&[]);
+            if children
+                .iter()
+                .any(|child_key| Some(*child_key) != merged && self.tree.nodes[*child_key].has_children())
+                || self.subtree_item_count(parent_key) > self.merge_threshold
+            {
+                break;
+            }
+            merged = Some(parent_key);
+            candidate = self.tree.nodes[parent_key].get_parent();
+        }
+
+        if let Some(merged_key) = merged {
+            let mut pending_node_keys = self.tree.nodes[merged_key].children().unwrap_or(&[]).to_vec();
+            while let Some(node_key) = pending_node_keys.pop() {
+                for item_key in self.node_items.remove(node_key).unwrap_or_default() {
+                    self.add_to_node(merged_key, item_key);
+                }
+                if let Some(children) = self.tree.nodes[node_key].children() {
+                    pending_node_keys.extend(children.iter());
+                }
+            }
+            self.tree.shrink_event(events, merged_key);
+        }
+    }
+
+    fn finish_events(&mut self, mut events: Vec<TreeEvent<D>>) {
+        if !events.is_empty() {
+            self.tree.update_neighbors_from_events(&mut events);
+            self.events.extend(events);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quad_tree::QuadItemTree;
+
+    fn random_tree(looseness: f32) -> QuadItemTree<()> {
+        let mut rng = fastrand::Rng::with_seed(7);
+        let mut tree = QuadItemTree::new(1.0, 64.0, [0.0, 0.0], 4).with_looseness(looseness);
+        for i in 0..300 {
+            let pos = [rng.f32() * 60.0 - 30.0, rng.f32() * 60.0 - 30.0];
+            if i % 2 == 0 {
+                tree.insert_point((), pos);
+            } else {
+                let extent = rng.f32() * 6.0;
+                tree.insert((), pos, [pos[0] + extent, pos[1] + extent]);
+            }
+        }
+        tree
+    }
+
+    fn sorted_pairs(pairs: impl IntoIterator<Item = (ItemKey, ItemKey)>) -> Vec<(ItemKey, ItemKey)> {
+        let mut pairs: Vec<_> = pairs.into_iter().map(|(a, b)| (a.min(b), a.max(b))).collect();
+        pairs.sort();
+        pairs
+    }
+
+    fn brute_force_pairs(tree: &QuadItemTree<()>, radius: f32) -> Vec<(ItemKey, ItemKey)> {
+        let entries: Vec<_> = tree.iter().collect();
+        let mut pairs = vec![];
+        for (i, (a_key, a)) in entries.iter().enumerate() {
+            for (b_key, b) in &entries[i + 1..] {
+                if aabb_gap_distance_squared(a.min, a.max, b.min, b.max) <= radius * radius {
+                    pairs.push((*a_key, *b_key));
+                }
+            }
+        }
+        sorted_pairs(pairs)
+    }
+
+    #[test]
+    fn pairs_match_brute_force() {
+        for looseness in [1.0, 1.5] {
+            let tree = random_tree(looseness);
+            let pairs = sorted_pairs(tree.overlapping_pairs());
+            assert!(!pairs.is_empty());
+            assert_eq!(pairs, brute_force_pairs(&tree, 0.0));
+            for radius in [0.5, 3.0] {
+                assert_eq!(sorted_pairs(tree.pairs_within(radius)), brute_force_pairs(&tree, radius));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "before any items are inserted")]
+    fn looseness_rejected_after_insert() {
+        let mut tree = QuadItemTree::new(1.0, 64.0, [0.0, 0.0], 4);
+        tree.insert_point((), [1.0, 1.0]);
+        let _ = tree.with_looseness(2.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "below bucket_capacity")]
+    fn merge_threshold_rejected_at_capacity() {
+        let _ = QuadItemTree::<()>::new(1.0, 64.0, [0.0, 0.0], 4).with_merge_threshold(4);
+    }
+
+    // Grown and Shrunk events as the parent or retained node and the number of new or removed nodes
+    fn topology_events(events: &[TreeEvent<2>]) -> Vec<(&'static str, NodeKey, usize)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                TreeEvent::Grown { parent, children } => Some(("grown", *parent, children.len())),
+                TreeEvent::Shrunk { retained, removed } => Some(("shrunk", *retained, removed.len())),
+                TreeEvent::NeighborSizesChanged { .. } => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_above_capacity_and_merges_at_threshold() {
+        let mut tree = QuadItemTree::new(1.0, 64.0, [0.0, 0.0], 4).with_merge_threshold(2);
+        let root = tree.tree().root();
+        let points = [[-10.0, -10.0], [10.0, -10.0], [-10.0, 10.0], [10.0, 10.0], [20.0, 20.0]];
+
+        // The root splits once it holds more than 4 items, and hands its items down to the new children
+        let mut item_keys: Vec<ItemKey> = points[..4].iter().map(|pos| tree.insert_point((), *pos)).collect();
+        assert!(tree.take_events().is_empty());
+        item_keys.push(tree.insert_point((), points[4]));
+        let events = tree.take_events();
+        assert_eq!(topology_events(&events), vec![("grown", root, 4)]);
+        let children = tree.tree().nodes[root].children().unwrap().to_vec();
+        let TreeEvent::Grown { children: new_children, .. } = &events[0] else {
+            panic!("expected a Grown event first");
+        };
+        assert_eq!(new_children.iter().map(|child| child.node_key).collect::<Vec<_>>(), children);
+        assert!(tree.node_items(root).is_empty());
+        for item_key in &item_keys {
+            let node_key = tree.entry(*item_key).unwrap().node();
+            assert!(children.contains(&node_key));
+            assert!(tree.node_items(node_key).contains(item_key));
+        }
+
+        // The children collapse once the root holds no more than 2 items
+        tree.remove(item_keys[4]);
+        tree.remove(item_keys[3]);
+        assert!(tree.take_events().is_empty());
+        tree.remove(item_keys[2]);
+        let events = tree.take_events();
+        assert_eq!(topology_events(&events), vec![("shrunk", root, 4)]);
+        let TreeEvent::Shrunk { removed, .. } = &events[0] else {
+            panic!("expected a Shrunk event first");
+        };
+        let mut removed_keys: Vec<NodeKey> = removed.iter().map(|snapshot| snapshot.node_key).collect();
+        removed_keys.sort();
+        let mut children = children;
+        children.sort();
+        assert_eq!(removed_keys, children);
+        assert!(!tree.tree().nodes[root].has_children());
+        assert_eq!(tree.node_items(root).len(), 2);
+        assert!(item_keys[..2].iter().all(|item_key| tree.entry(*item_key).unwrap().node() == root));
+    }
+}
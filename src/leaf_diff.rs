@@ -0,0 +1,198 @@
+use crate::{node_traits::*, tree_traits::*, NodeKey};
+use ahash::{AHashMap as HashMap, AHashSet as HashSet};
+
+/// The change in the leaf set made by one update. Removing `removed` from the leaves before the update and adding
+/// `added` gives the leaves after it. Nodes that became leaves and stopped being leaves again in the same update
+/// are left out.
+#[derive(Debug, Clone)]
+pub struct LeafDiff<const D: usize, F = ()> {
+    /// New leaves, with their current bounds.
+    pub added: Vec<NodeSnapshot<D, F>>,
+    /// Leaves that were split or merged away, with their bounds from before the update.
+    pub removed: Vec<NodeSnapshot<D, F>>,
+    /// Leaves present before and after the update whose neighbor sizes changed.
+    pub neighbor_changed: Vec<NodeKey>,
+}
+
+impl<const D: usize, F> Default for LeafDiff<D, F> {
+    fn default() -> Self {
+        Self {
+            added: vec![],
+            removed: vec![],
+            neighbor_changed: vec![],
+        }
+    }
+}
+
+impl<const D: usize, F: Copy> LeafDiff<D, F> {
+    /// Builds the diff from the events of one or more consecutive updates, before the tree changes again.
+    pub fn from_events<Tree>(tree: &Tree, events: &[TreeEvent<D, F>]) -> Self
+    where
+        Tree: TreeBehaviour<D, Face = F> + ?Sized,
+        Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+    {
+        let mut added = SnapshotSet::default();
+        let mut removed = SnapshotSet::default();
+        let mut neighbor_changed = vec![];
+        for (i, event) in events.iter().enumerate() {
+            match event {
+                TreeEvent::Grown { parent, children } => {
+                    if added.remove(*parent).is_none() {
+                        removed.insert(leaf_snapshot(tree, &events[i + 1..], *parent));
+                    }
+                    for child in children {
+                        added.insert(*child);
+                    }
+                }
+                TreeEvent::Shrunk { retained, removed: removed_nodes } => {
+                    for snapshot in removed_nodes.iter().filter(|snapshot| snapshot.leaf) {
+                        if added.remove(snapshot.node_key).is_none() {
+                            removed.insert(*snapshot);
+                        }
+                    }
+                    if removed.remove(*retained).is_none() {
+                        added.insert(leaf_snapshot(tree, &events[i + 1..], *retained));
+                    }
+                }
+                TreeEvent::NeighborSizesChanged { node, .. } => neighbor_changed.push(*node),
+            }
+        }
+
+        let mut seen = HashSet::new();
+        neighbor_changed.retain(|node_key| {
+            !added.contains(*node_key)
+                && tree.get_node(*node_key).is_some_and(|node| !node.has_children())
+                && seen.insert(*node_key)
+        });
+        Self {
+            added: added.into_vec(),
+            removed: removed.into_vec(),
+            neighbor_changed,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.neighbor_changed.is_empty()
+    }
+}
+
+// Snapshot of a node as a leaf. Nodes removed by later events are taken from those events.
+fn leaf_snapshot<Tree, const D: usize>(
+    tree: &Tree,
+    later_events: &[TreeEvent<D, Tree::Face>],
+    node_key: NodeKey,
+) -> NodeSnapshot<D, Tree::Face>
+where
+    Tree: TreeBehaviour<D> + ?Sized,
+    Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+{
+    let mut snapshot = match tree.get_node(node_key) {
+        Some(node) => tree.snapshot(node_key, node, tree.depth(node_key)),
+        None => later_events
+            .iter()
+            .find_map(|event| match event {
+                TreeEvent::Shrunk { removed, .. } => removed.iter().find(|snapshot| snapshot.node_key == node_key),
+                _ => None,
+            })
+            .copied()
+            .expect("node removed without a Shrunk event"),
+    };
+    snapshot.leaf = true;
+    snapshot
+}
+
+// Snapshots in insertion order with removal by key
+struct SnapshotSet<const D: usize, F> {
+    snapshots: Vec<Option<NodeSnapshot<D, F>>>,
+    index: HashMap<NodeKey, usize>,
+}
+
+impl<const D: usize, F> Default for SnapshotSet<D, F> {
+    fn default() -> Self {
+        Self {
+            snapshots: vec![],
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl<const D: usize, F> SnapshotSet<D, F> {
+    fn insert(&mut self, snapshot: NodeSnapshot<D, F>) {
+        self.index.insert(snapshot.node_key, self.snapshots.len());
+        self.snapshots.push(Some(snapshot));
+    }
+
+    fn remove(&mut self, node_key: NodeKey) -> Option<NodeSnapshot<D, F>> {
+        self.index
+            .remove(&node_key)
+            .and_then(|index| self.snapshots[index].take())
+    }
+
+    fn contains(&self, node_key: NodeKey) -> bool {
+        self.index.contains_key(&node_key)
+    }
+
+    fn into_vec(self) -> Vec<NodeSnapshot<D, F>> {
+        self.snapshots.into_iter().flatten().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        planet_tree::PlanetTree,
+        quad_tree::{QuadTree, QuadTreeNode},
+    };
+
+    fn leaf_set<Tree, const D: usize>(tree: &Tree) -> HashSet<NodeKey>
+    where
+        Tree: TreeBehaviour<D>,
+        Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D>,
+    {
+        tree.leaf_keys().into_iter().collect()
+    }
+
+    fn apply<const D: usize, F>(mut leaves: HashSet<NodeKey>, diff: &LeafDiff<D, F>) -> HashSet<NodeKey> {
+        for snapshot in &diff.removed {
+            assert!(leaves.remove(&snapshot.node_key), "removed a node that was not a leaf");
+        }
+        for snapshot in &diff.added {
+            assert!(leaves.insert(snapshot.node_key), "added a node that was already a leaf");
+        }
+        leaves
+    }
+
+    #[test]
+    fn applying_diff_gives_current_leaves() {
+        let mut tree = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        for point in [[10.0, 10.0], [11.0, 9.0], [-20.0, 30.0], [10.0, 10.0]] {
+            let previous_leaves = leaf_set(&tree);
+            let diff = tree.insert_and_diff(|node: &QuadTreeNode| node.contains_point(point));
+            assert_eq!(apply(previous_leaves.clone(), &diff), leaf_set(&tree));
+            for node_key in &diff.neighbor_changed {
+                assert!(previous_leaves.contains(node_key));
+            }
+        }
+    }
+
+    #[test]
+    fn applying_diff_of_budget_refinement_gives_current_leaves() {
+        let mut tree = PlanetTree::new(1.0, 64.0, [0.0, 0.0, 0.0]);
+        for (target, max_leaves) in [([32.0, 0.0, 0.0], 200), ([0.0, -32.0, 0.0], 120), ([0.0, 0.0, 32.0], 300)] {
+            let previous_leaves = leaf_set(&tree);
+            let events = tree.refine_with_budget_and_update_neighbors(
+                |node| {
+                    let (min, max) = node.world_bounds();
+                    node.size() / (aabb_distance_squared(min, max, target).sqrt() + 1.0)
+                },
+                max_leaves,
+            );
+            let diff = LeafDiff::from_events(&tree, &events);
+            assert_eq!(apply(previous_leaves, &diff), leaf_set(&tree));
+            for snapshot in &diff.added {
+                assert_eq!(snapshot.face, tree.nodes[snapshot.node_key].direction());
+            }
+        }
+    }
+}
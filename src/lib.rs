@@ -1,18 +1,45 @@
+mod incremental;
+mod interest;
+mod item_tree;
+mod leaf_diff;
 mod node_traits;
 mod tree_traits;
 mod ntree;
 mod oct_tree_node;
+mod observer;
+#[cfg(feature = "rayon")]
+mod parallel;
+mod pins;
 mod planet_tree_impl;
 mod planet_tree_node;
 mod quad_tree_node;
+mod topology;
+mod raycast;
+#[cfg(feature = "serde")]
+mod serialization;
+#[cfg(test)]
+mod test_utils;
 
 
 use slotmap::new_key_type;
 new_key_type! {pub struct NodeKey;}
+new_key_type! {pub struct ItemKey;}
+new_key_type! {pub struct VolumeKey;}
+
+#[cfg(feature = "serde")]
+pub use serialization::TreeWithData;
+pub use pins::Pins;
+pub use topology::TopologyError;
 
 pub mod planet_tree {
     pub use crate::node_traits::*;
     pub use crate::tree_traits::*;  
+    pub use crate::incremental::*;
+    pub use crate::interest::*;
+    pub use crate::leaf_diff::*;
+    pub use crate::observer::*;
+    #[cfg(feature = "rayon")]
+    pub use crate::parallel::*;
     pub use crate::planet_tree_impl::*;
     pub use crate::planet_tree_node::PlanetTreeNode;
 }
@@ -20,13 +47,31 @@ pub mod planet_tree {
 pub mod quad_tree {
     pub use crate::node_traits::*;
     pub use crate::tree_traits::*;    
+    pub use crate::incremental::*;
+    pub use crate::interest::*;
+    pub use crate::leaf_diff::*;
+    pub use crate::observer::*;
+    #[cfg(feature = "rayon")]
+    pub use crate::parallel::*;
     pub type QuadTree = crate::ntree::NTree<QuadTreeNode, 2>;
+    pub type QuadItemTree<I> = crate::item_tree::ItemTree<QuadTreeNode, I, 2>;
+    pub use crate::item_tree::*;
     pub use crate::quad_tree_node::QuadTreeNode;    
+    pub use crate::raycast::*;
 }
 
 pub mod oct_tree {
     pub use crate::node_traits::*;
     pub use crate::tree_traits::*;
+    pub use crate::incremental::*;
+    pub use crate::interest::*;
+    pub use crate::leaf_diff::*;
+    pub use crate::observer::*;
+    #[cfg(feature = "rayon")]
+    pub use crate::parallel::*;
     pub type OctTree = crate::ntree::NTree<OctTreeNode, 3>;
+    pub type OctItemTree<I> = crate::item_tree::ItemTree<OctTreeNode, I, 3>;
+    pub use crate::item_tree::*;
     pub use crate::oct_tree_node::OctTreeNode;    
+    pub use crate::raycast::*;
 }
\ No newline at end of file
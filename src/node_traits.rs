@@ -119,6 +119,19 @@ where
     fn neighbor_offsets(&self) -> &[f32];
 }
 
+/// Refinement bookkeeping kept in every node.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RefineState {
+    /// Number of consecutive updates the node wanted to merge without merging.
+    pub merge_frames: u32,
+}
+
+pub trait RefineStateBehaviour {
+    fn refine_state(&self) -> &RefineState;
+    fn refine_state_mut(&mut self) -> &mut RefineState;
+}
+
 pub trait Boundary<const D: usize> {
     fn from_bounds(size: f32, pos: [f32; D]) -> Self;
     fn pos(&self) -> [f32; D];
@@ -150,4 +163,110 @@ pub trait Boundary<const D: usize> {
 
         (min, max)
     }
+
+    fn overlap_aabb(&self, min: [f32; D], max: [f32; D]) -> Overlap {
+        let (node_min, node_max) = self.bounds();
+        aabb_overlap_aabb(node_min, node_max, min, max)
+    }
+
+    fn overlap_sphere(&self, center: [f32; D], radius: f32) -> Overlap {
+        let (node_min, node_max) = self.bounds();
+        aabb_overlap_sphere(node_min, node_max, center, radius)
+    }
+
+    fn distance_squared(&self, point: [f32; D]) -> f32 {
+        let (min, max) = self.bounds();
+        aabb_distance_squared(min, max, point)
+    }
+}
+
+/// How a node's bounds relate to a query volume.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Overlap {
+    Disjoint,
+    Partial,
+    Contained,
+}
+
+/// How the box `min`..`max` overlaps the box `query_min`..`query_max`. Touching boxes count as overlapping.
+pub fn aabb_overlap_aabb<const D: usize>(
+    min: [f32; D],
+    max: [f32; D],
+    query_min: [f32; D],
+    query_max: [f32; D],
+) -> Overlap {
+    if !aabb_intersects_aabb(min, max, query_min, query_max) {
+        Overlap::Disjoint
+    } else if aabb_contains_aabb(query_min, query_max, min, max) {
+        Overlap::Contained
+    } else {
+        Overlap::Partial
+    }
+}
+
+/// How the box `min`..`max` overlaps the sphere. A box touching the sphere counts as overlapping.
+pub fn aabb_overlap_sphere<const D: usize>(min: [f32; D], max: [f32; D], center: [f32; D], radius: f32) -> Overlap {
+    let radius_squared = radius * radius;
+    if aabb_distance_squared(min, max, center) > radius_squared {
+        Overlap::Disjoint
+    } else if aabb_max_distance_squared(min, max, center) <= radius_squared {
+        Overlap::Contained
+    } else {
+        Overlap::Partial
+    }
+}
+
+/// Touching boxes count as intersecting.
+pub fn aabb_intersects_aabb<const D: usize>(
+    a_min: [f32; D],
+    a_max: [f32; D],
+    b_min: [f32; D],
+    b_max: [f32; D],
+) -> bool {
+    (0..D).all(|i| a_min[i] <= b_max[i] && b_min[i] <= a_max[i])
+}
+
+/// True if the box `inner` lies completely inside the box `outer`.
+pub fn aabb_contains_aabb<const D: usize>(
+    outer_min: [f32; D],
+    outer_max: [f32; D],
+    inner_min: [f32; D],
+    inner_max: [f32; D],
+) -> bool {
+    (0..D).all(|i| outer_min[i] <= inner_min[i] && inner_max[i] <= outer_max[i])
+}
+
+/// Squared distance from a point to the closest point of a box, zero if the point is inside.
+pub fn aabb_distance_squared<const D: usize>(min: [f32; D], max: [f32; D], point: [f32; D]) -> f32 {
+    (0..D)
+        .map(|i| {
+            let d = (min[i] - point[i]).max(point[i] - max[i]).max(0.0);
+            d * d
+        })
+        .sum()
+}
+
+/// Squared distance between the closest points of two boxes, zero if they touch or overlap.
+pub fn aabb_gap_distance_squared<const D: usize>(
+    a_min: [f32; D],
+    a_max: [f32; D],
+    b_min: [f32; D],
+    b_max: [f32; D],
+) -> f32 {
+    (0..D)
+        .map(|i| {
+            let d = (a_min[i] - b_max[i]).max(b_min[i] - a_max[i]).max(0.0);
+            d * d
+        })
+        .sum()
+}
+
+/// Squared distance from a point to the farthest corner of a box.
+pub fn aabb_max_distance_squared<const D: usize>(min: [f32; D], max: [f32; D], point: [f32; D]) -> f32 {
+    (0..D)
+        .map(|i| {
+            let d = (point[i] - min[i]).abs().max((max[i] - point[i]).abs());
+            d * d
+        })
+        .sum()
 }
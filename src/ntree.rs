@@ -1,16 +1,63 @@
-use crate::{node_traits::*, tree_traits::*, NodeKey};
+use crate::{node_traits::*, observer::*, pins::Pins, tree_traits::*, NodeKey};
 use slotmap::SlotMap;
 
 /// Shared struct between 2d QuadTree and 3d OctTree.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(
+        bound(serialize = "T: serde::Serialize", deserialize = "T: serde::Deserialize<'de>"),
+        try_from = "SavedNTree<T, D>"
+    )
+)]
 pub struct NTree<T, const D: usize>
 where
     T: ChildBehaviour<D> + NeighborBehaviour<D> + Boundary<D>,
 {
     pub nodes: SlotMap<NodeKey, T>,
     pub min_size: f32,
+    pub max_depth: Option<usize>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    min_size_fn: Option<MinSizeFn<T>>,
+    pins: Pins,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    observer: Option<BoxedObserver<D>>,
     root: NodeKey,
 }
 
+/// The serialized fields of `NTree`, checked before they become a tree.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+#[serde(bound(deserialize = "T: serde::Deserialize<'de>"))]
+struct SavedNTree<T, const D: usize> {
+    nodes: SlotMap<NodeKey, T>,
+    min_size: f32,
+    max_depth: Option<usize>,
+    pins: Pins,
+    root: NodeKey,
+}
+
+#[cfg(feature = "serde")]
+impl<T, const D: usize> TryFrom<SavedNTree<T, D>> for NTree<T, D>
+where
+    T: ChildBehaviour<D> + NeighborBehaviour<D> + Boundary<D>,
+{
+    type Error = String;
+
+    fn try_from(saved: SavedNTree<T, D>) -> Result<Self, Self::Error> {
+        crate::serialization::check_links(&saved.nodes, &[saved.root], &saved.pins)?;
+        Ok(Self {
+            nodes: saved.nodes,
+            min_size: saved.min_size,
+            max_depth: saved.max_depth,
+            min_size_fn: None,
+            pins: saved.pins,
+            observer: None,
+            root: saved.root,
+        })
+    }
+}
+
 impl<T, const D: usize> NTree<T, D>
 where
     T: ChildBehaviour<D> + NeighborBehaviour<D> + Boundary<D>,
@@ -21,24 +68,94 @@ where
 
         Self {
             min_size,
+            max_depth: None,
+            min_size_fn: None,
+            pins: Pins::default(),
+            observer: None,
             nodes,
             root,
         }
     }
 
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Sets a function giving the smallest node size allowed around a node, e.g. finer in gameplay areas than over the ocean.
+    /// Nodes are never split below `min_size`, whatever the function returns.
+    pub fn with_min_size_fn(mut self, f: impl Fn(&T) -> f32 + Send + Sync + 'static) -> Self {
+        self.set_min_size_fn(Some(Box::new(f)));
+        self
+    }
+
+    pub fn set_min_size_fn(&mut self, f: Option<MinSizeFn<T>>) {
+        self.min_size_fn = f;
+    }
+
+    /// Registers an observer that is called while the tree changes, see `TreeObserver`.
+    pub fn with_observer(mut self, observer: impl TreeObserver<D> + Send + Sync + 'static) -> Self {
+        self.set_observer(Some(Box::new(observer)));
+        self
+    }
+
+    /// Replaces the observer and returns the previous one.
+    pub fn set_observer(&mut self, observer: Option<BoxedObserver<D>>) -> Option<BoxedObserver<D>> {
+        std::mem::replace(&mut self.observer, observer)
+    }
+
     pub fn iter_leaf_nodes(&self) -> impl Iterator<Item = (NodeKey, &T)> {
         self.nodes.iter().filter(|(_, node)| !node.has_children())
     }
+
+    /// Like `iter_leaf_nodes`, but in the stable order of `leaf_keys`.
+    pub fn iter_leaf_nodes_ordered(&self) -> impl Iterator<Item = (NodeKey, &T)>
+    where
+        T: std::fmt::Debug,
+    {
+        self.leaf_keys().into_iter().map(|node_key| (node_key, &self.nodes[node_key]))
+    }
+
+    pub fn root(&self) -> NodeKey {
+        self.root
+    }
 }
 
 impl<T, const D: usize> TreeBehaviour<D> for NTree<T, D>
 where
     T: ChildBehaviour<D> + NeighborBehaviour<D> + Boundary<D> + std::fmt::Debug,
 {
+    type Face = ();
+
     fn min_size(&self) -> f32 {
         self.min_size
     }
 
+    fn min_size_for(&self, node: &T) -> f32 {
+        match &self.min_size_fn {
+            Some(f) => f(node).max(self.min_size),
+            None => self.min_size,
+        }
+    }
+
+    fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    fn observer_mut(&mut self) -> Option<&mut BoxedObserver<D>> {
+        self.observer.as_mut()
+    }
+
+    fn face(&self, _node: &T) {}
+
+    fn pins(&self) -> Option<&Pins> {
+        Some(&self.pins)
+    }
+
+    fn pins_mut(&mut self) -> Option<&mut Pins> {
+        Some(&mut self.pins)
+    }
+
     fn root_items(&self) -> Vec<NodeKey> {
         vec![self.root]
     }
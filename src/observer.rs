@@ -0,0 +1,49 @@
+use crate::{tree_traits::*, NodeKey};
+
+/// An observer registered on a tree, see `TreeObserver`.
+pub type BoxedObserver<const D: usize, F = ()> = Box<dyn TreeObserver<D, F> + Send + Sync>;
+
+/// Callbacks called while a tree changes, so data keyed by `NodeKey` can be kept up to date in place instead of
+/// matching on the returned `TreeEvent`s. Every refinement path calls them, in the order the changes are made.
+///
+/// The callbacks see each change on its own: where events fold several splits or merges together, the observer is
+/// called once per split or merge.
+pub trait TreeObserver<const D: usize, F = ()> {
+    /// `parent` was split into `children`. Called right after the children were added.
+    fn on_split(&mut self, _parent: NodeKey, _children: &[NodeSnapshot<D, F>]) {}
+
+    /// All descendants of `parent` were removed, intermediate nodes included. Called right after they were removed.
+    fn on_merge(&mut self, _parent: NodeKey, _removed: &[NodeSnapshot<D, F>]) {}
+
+    /// A side of an existing leaf got a new neighbor size or offsets. Called once per changed side, after the neighbor
+    /// sizes of all affected leaves were updated.
+    fn on_neighbor_changed(&mut self, _node: NodeKey, _change: &NeighborChange<D>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quad_tree::QuadTree;
+    use std::sync::{Arc, Mutex};
+
+    struct MergeCounter(Arc<Mutex<usize>>);
+
+    impl TreeObserver<2> for MergeCounter {
+        fn on_merge(&mut self, _parent: NodeKey, removed: &[NodeSnapshot<2>]) {
+            assert!(!removed.is_empty());
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+
+    #[test]
+    fn merge_only_reported_when_nodes_are_removed() {
+        let merges = Arc::new(Mutex::new(0));
+        let mut tree = QuadTree::new(1.0, 64.0, [0.0, 0.0]).with_observer(MergeCounter(merges.clone()));
+        tree.insert(|node| node.size > 16.0);
+        tree.insert(|node| node.size > 16.0);
+        assert_eq!(*merges.lock().unwrap(), 0);
+
+        tree.insert(|node| node.size > 32.0);
+        assert_eq!(*merges.lock().unwrap(), 4);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{node_traits::*, NodeKey};
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct OctTreeNode {
     pub size: f32,
     pub pos: [f32; 3],
@@ -8,6 +9,7 @@ pub struct OctTreeNode {
     pub neighbor_offsets: [f32; 12],
     pub parent: Option<NodeKey>,
     pub children: Option<[NodeKey; 8]>,
+    pub refine_state: RefineState,
 }
 
 impl Boundary<3> for OctTreeNode {
@@ -19,6 +21,7 @@ impl Boundary<3> for OctTreeNode {
             neighbor_offsets: [0.0; 12],
             parent: None,
             children: None,
+            refine_state: RefineState::default(),
         }
     }
 
@@ -63,7 +66,7 @@ impl ChildBehaviour<3> for OctTreeNode {
 
     fn take_children(&mut self) -> Vec<NodeKey> {
         if let Some(children) = self.children.take() {
-            Vec::from_iter(children.into_iter())
+            Vec::from_iter(children)
         } else {
             vec![]
         }
@@ -84,3 +87,12 @@ impl NeighborBehaviour<3> for OctTreeNode {
         self.neighbor_offsets.as_slice()
     }
 }
+
+impl RefineStateBehaviour for OctTreeNode {
+    fn refine_state(&self) -> &RefineState {
+        &self.refine_state
+    }
+    fn refine_state_mut(&mut self) -> &mut RefineState {
+        &mut self.refine_state
+    }
+}
@@ -0,0 +1,176 @@
+use rayon::prelude::*;
+
+use crate::{node_traits::*, tree_traits::*, NodeKey};
+
+/// Decisions for one node, computed in parallel before the tree is changed.
+enum RefinePlan {
+    Keep,
+    Descend(Vec<RefinePlan>),
+    Split(Vec<RefinePlan>),
+    Shrink,
+}
+
+/// Parallel versions of `insert`, available with the `rayon` feature.
+///
+/// Predicates are evaluated and new subtrees are planned in parallel, one task per child, without touching the tree.
+/// The plan is then applied serially in the same order as `insert` walks the tree, so the resulting tree, its node keys
+/// and the returned events are the same as for the serial path.
+pub trait ParallelTreeBehaviour<const D: usize>
+where
+    Self: TreeNeighbourBehaviour<D> + Sync,
+    <Self as NodeStorage>::NodeType:
+        Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug + Send + Sync,
+{
+    fn par_insert(&mut self, f: impl Fn(&Self::NodeType) -> bool + Sync) -> Vec<TreeEvent<D, Self::Face>> {
+        let root_items = self.root_items();
+        let plans: Vec<RefinePlan> = root_items
+            .par_iter()
+            .map(|node_key| plan_existing(self, *node_key, 0, &f))
+            .collect();
+
+        let mut events = vec![];
+        for (node_key, plan) in root_items.into_iter().zip(plans).rev() {
+            apply_plan(self, &mut events, node_key, plan);
+        }
+        events
+    }
+
+    fn par_insert_and_update_neighbors(
+        &mut self,
+        f: impl Fn(&Self::NodeType) -> bool + Sync,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        let mut events = self.par_insert(f);
+        self.update_neighbors_from_events(&mut events);
+        events
+    }
+}
+
+impl<Tree, const D: usize> ParallelTreeBehaviour<D> for Tree
+where
+    Tree: TreeNeighbourBehaviour<D> + Sync,
+    <Tree as NodeStorage>::NodeType:
+        Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug + Send + Sync,
+{
+}
+
+fn plan_existing<Tree, const D: usize>(
+    tree: &Tree,
+    node_key: NodeKey,
+    depth: usize,
+    f: &(impl Fn(&Tree::NodeType) -> bool + Sync),
+) -> RefinePlan
+where
+    Tree: TreeBehaviour<D> + Sync + ?Sized,
+    Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug + Send + Sync,
+{
+    let node = tree.get_node_unchecked(node_key);
+    let split = f(node);
+    if !split && !tree.is_merge_protected(node_key) {
+        return if node.has_children() {
+            RefinePlan::Shrink
+        } else {
+            RefinePlan::Keep
+        };
+    }
+
+    if let Some(children) = node.children() {
+        RefinePlan::Descend(
+            children
+                .par_iter()
+                .map(|child_key| plan_existing(tree, *child_key, depth + 1, f))
+                .collect(),
+        )
+    } else if split && tree.can_split_node(node, depth) {
+        plan_split(tree, node, depth, f)
+    } else {
+        RefinePlan::Keep
+    }
+}
+
+fn plan_split<Tree, const D: usize>(
+    tree: &Tree,
+    node: &Tree::NodeType,
+    depth: usize,
+    f: &(impl Fn(&Tree::NodeType) -> bool + Sync),
+) -> RefinePlan
+where
+    Tree: TreeBehaviour<D> + Sync + ?Sized,
+    Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug + Send + Sync,
+{
+    let num_children = 2usize.pow(D as u32);
+    RefinePlan::Split(
+        (0..num_children)
+            .into_par_iter()
+            .map(|child_index| {
+                let child = tree.child_node(node, child_index);
+                if f(&child) && tree.can_split_node(&child, depth + 1) {
+                    plan_split(tree, &child, depth + 1, f)
+                } else {
+                    RefinePlan::Keep
+                }
+            })
+            .collect(),
+    )
+}
+
+// `insert` pushes children on a stack, so the last child is handled first. The plan is applied in the same order.
+fn apply_plan<Tree, const D: usize>(
+    tree: &mut Tree,
+    events: &mut Vec<TreeEvent<D, Tree::Face>>,
+    node_key: NodeKey,
+    plan: RefinePlan,
+) where
+    Tree: TreeBehaviour<D> + ?Sized,
+    Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+{
+    match plan {
+        RefinePlan::Keep => {}
+        RefinePlan::Shrink => tree.shrink_event(events, node_key),
+        RefinePlan::Descend(plans) => {
+            let children = tree.get_node_unchecked(node_key).children().unwrap().to_vec();
+            for (child_key, plan) in children.into_iter().zip(plans).rev() {
+                apply_plan(tree, events, child_key, plan);
+            }
+        }
+        RefinePlan::Split(plans) => {
+            let parent_pos = tree.get_node_unchecked(node_key).pos();
+            let new_children = tree.create_children(node_key);
+            tree.grow_event(events, parent_pos, node_key, &new_children);
+            for (child_key, plan) in new_children.into_iter().zip(plans).rev() {
+                apply_plan(tree, events, child_key, plan);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{planet_tree::PlanetTree, quad_tree::QuadTree, test_utils::as_text};
+
+    #[test]
+    fn quad_tree_matches_serial_insert() {
+        let mut serial = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        let mut parallel = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        for point in [[10.0, 10.0], [12.0, -20.0], [-30.0, 5.0]] {
+            let f = |node: &crate::quad_tree::QuadTreeNode| node.contains_point(point);
+            let serial_events = serial.insert_and_update_neighbors(f);
+            let parallel_events = parallel.par_insert_and_update_neighbors(f);
+            assert_eq!(as_text(serial_events), as_text(parallel_events));
+            assert_eq!(as_text(serial.nodes.iter()), as_text(parallel.nodes.iter()));
+        }
+    }
+
+    #[test]
+    fn planet_tree_matches_serial_insert() {
+        let mut serial = PlanetTree::new(1.0, 64.0, [0.0, 0.0, 0.0]);
+        let mut parallel = PlanetTree::new(1.0, 64.0, [0.0, 0.0, 0.0]);
+        for min_size in [8.0, 16.0, 4.0] {
+            let f = |node: &crate::planet_tree::PlanetTreeNode| node.size() > min_size && node.pos()[0] >= 0.0;
+            let serial_events = serial.insert_and_update_neighbors(f);
+            let parallel_events = parallel.par_insert_and_update_neighbors(f);
+            assert_eq!(as_text(serial_events), as_text(parallel_events));
+            assert_eq!(as_text(serial.nodes.iter()), as_text(parallel.nodes.iter()));
+        }
+    }
+}
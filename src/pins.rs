@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+
+use crate::NodeKey;
+
+/// Reference counted pins. A pinned node is never merged by refinement, and neither are its ancestors.
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Pins {
+    #[cfg_attr(feature = "serde", serde(with = "key_counts"))]
+    counts: BTreeMap<NodeKey, u32>,
+    // Pins on the node and all of its descendants
+    #[cfg_attr(feature = "serde", serde(with = "key_counts"))]
+    subtree_counts: BTreeMap<NodeKey, u32>,
+}
+
+impl Pins {
+    /// Number of times the node itself is pinned.
+    pub fn count(&self, node_key: NodeKey) -> u32 {
+        self.counts.get(&node_key).copied().unwrap_or(0)
+    }
+
+    pub fn is_pinned(&self, node_key: NodeKey) -> bool {
+        self.counts.contains_key(&node_key)
+    }
+
+    /// True if the node or any of its descendants is pinned.
+    pub fn is_protected(&self, node_key: NodeKey) -> bool {
+        self.subtree_counts.contains_key(&node_key)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Pinned nodes and their pin counts, in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (NodeKey, u32)> + '_ {
+        self.counts.iter().map(|(node_key, count)| (*node_key, *count))
+    }
+
+    /// Every node with a count, pinned itself or through a descendant.
+    #[cfg(feature = "serde")]
+    pub(crate) fn node_keys(&self) -> impl Iterator<Item = NodeKey> + '_ {
+        self.counts.keys().chain(self.subtree_counts.keys()).copied()
+    }
+
+    /// `path` is the node followed by all of its ancestors.
+    pub(crate) fn add(&mut self, path: &[NodeKey], count: u32) {
+        if let Some(node_key) = path.first() {
+            *self.counts.entry(*node_key).or_insert(0) += count;
+        }
+        for node_key in path {
+            *self.subtree_counts.entry(*node_key).or_insert(0) += count;
+        }
+    }
+
+    /// `path` is the node followed by all of its ancestors. Returns false if the node was not pinned.
+    pub(crate) fn remove(&mut self, path: &[NodeKey]) -> bool {
+        match path.first() {
+            Some(node_key) if self.is_pinned(*node_key) => {
+                decrement(&mut self.counts, *node_key, 1);
+                for node_key in path {
+                    decrement(&mut self.subtree_counts, *node_key, 1);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Moves the pins of removed descendants onto the node that replaces them.
+    pub(crate) fn transfer(&mut self, removed: &[NodeKey], retained: NodeKey) {
+        let mut count = 0;
+        for node_key in removed {
+            count += self.counts.remove(node_key).unwrap_or(0);
+            self.subtree_counts.remove(node_key);
+        }
+        if count > 0 {
+            *self.counts.entry(retained).or_insert(0) += count;
+        }
+    }
+}
+
+fn decrement(counts: &mut BTreeMap<NodeKey, u32>, node_key: NodeKey, count: u32) {
+    if let Some(v) = counts.get_mut(&node_key) {
+        *v = v.saturating_sub(count);
+        if *v == 0 {
+            counts.remove(&node_key);
+        }
+    }
+}
+
+// Node keys are not strings, which formats like JSON require for map keys,
+// so the counts are saved as a list of pairs
+#[cfg(feature = "serde")]
+mod key_counts {
+    use crate::NodeKey;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer>(
+        counts: &BTreeMap<NodeKey, u32>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(counts.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<NodeKey, u32>, D::Error> {
+        let counts = Vec::<(NodeKey, u32)>::deserialize(deserializer)?;
+        Ok(counts.into_iter().collect())
+    }
+}
@@ -1,19 +1,56 @@
-use ahash::AHashMap as HashMap;
-
 use crate::{
     node_traits::*,
     tree_traits::*,
     NodeKey,
     planet_tree_node::*, 
+    pins::Pins,
+    observer::*,
 };
 use slotmap::SlotMap;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "SavedPlanetTree"))]
 pub struct PlanetTree {
     pub nodes: SlotMap<NodeKey, PlanetTreeNode>,
     pub min_size: f32,
+    pub max_depth: Option<usize>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    min_size_fn: Option<MinSizeFn<PlanetTreeNode>>,
+    pins: Pins,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    observer: Option<BoxedObserver<2, Direction>>,
+    roots: [NodeKey; 6],
+}
+
+/// The serialized fields of `PlanetTree`, checked before they become a tree.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct SavedPlanetTree {
+    nodes: SlotMap<NodeKey, PlanetTreeNode>,
+    min_size: f32,
+    max_depth: Option<usize>,
+    pins: Pins,
     roots: [NodeKey; 6],
 }
 
+#[cfg(feature = "serde")]
+impl TryFrom<SavedPlanetTree> for PlanetTree {
+    type Error = String;
+
+    fn try_from(saved: SavedPlanetTree) -> Result<Self, Self::Error> {
+        crate::serialization::check_links(&saved.nodes, &saved.roots, &saved.pins)?;
+        Ok(Self {
+            nodes: saved.nodes,
+            min_size: saved.min_size,
+            max_depth: saved.max_depth,
+            min_size_fn: None,
+            pins: saved.pins,
+            observer: None,
+            roots: saved.roots,
+        })
+    }
+}
+
 impl PlanetTree {
     pub fn new(min_size: f32, size: f32, pos: [f32; 3]) -> Self {
         let mut nodes = SlotMap::default();
@@ -40,13 +77,135 @@ impl PlanetTree {
         Self {
             nodes,
             min_size,
+            max_depth: None,
+            min_size_fn: None,
+            pins: Pins::default(),
+            observer: None,
             roots: node_keys.try_into().unwrap(),
         }
     }
 
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Sets a function giving the smallest node size allowed around a node, e.g. based on its world position.
+    /// Nodes are never split below `min_size`, whatever the function returns.
+    pub fn with_min_size_fn(mut self, f: impl Fn(&PlanetTreeNode) -> f32 + Send + Sync + 'static) -> Self {
+        self.set_min_size_fn(Some(Box::new(f)));
+        self
+    }
+
+    pub fn set_min_size_fn(&mut self, f: Option<MinSizeFn<PlanetTreeNode>>) {
+        self.min_size_fn = f;
+    }
+
+    /// Registers an observer that is called while the tree changes, see `TreeObserver`.
+    pub fn with_observer(mut self, observer: impl TreeObserver<2, Direction> + Send + Sync + 'static) -> Self {
+        self.set_observer(Some(Box::new(observer)));
+        self
+    }
+
+    /// Replaces the observer and returns the previous one.
+    pub fn set_observer(&mut self, observer: Option<BoxedObserver<2, Direction>>) -> Option<BoxedObserver<2, Direction>> {
+        std::mem::replace(&mut self.observer, observer)
+    }
+
+    /// Center of the cube the faces were created around.
+    pub fn center(&self) -> [f32; 3] {
+        let x_neg = &self.nodes[self.roots[Direction::XNeg as usize]];
+        let mut center = x_neg.world_position();
+        center[0] += x_neg.size() / 2.0;
+        center
+    }
+
+    /// Edge length of the cube faces.
+    pub fn size(&self) -> f32 {
+        self.nodes[self.roots[0]].size()
+    }
+
     pub fn iter_leaf_nodes(&self) -> impl Iterator<Item = (NodeKey, &PlanetTreeNode)> {
         self.nodes.iter().filter(|(_, node)| !node.has_children())
     }
+
+    /// Like `iter_leaf_nodes`, but in the stable order of `leaf_keys`.
+    pub fn iter_leaf_nodes_ordered(&self) -> impl Iterator<Item = (NodeKey, &PlanetTreeNode)> {
+        self.leaf_keys().into_iter().map(|node_key| (node_key, &self.nodes[node_key]))
+    }
+
+    /// Same as `query_aabb`, but the box is given in world space.
+    pub fn query_aabb_world(&self, min: [f32; 3], max: [f32; 3], include_covered: bool) -> Vec<NodeKey> {
+        self.query(
+            |node| {
+                let (node_min, node_max) = node.world_bounds();
+                aabb_overlap_aabb(node_min, node_max, min, max)
+            },
+            include_covered,
+        )
+    }
+
+    /// Returns the `k` leaves whose world-space patches are closest to the point, sorted by distance.
+    pub fn nearest_leaves_world(&self, point: [f32; 3], k: usize) -> Vec<(NodeKey, f32)> {
+        self.nearest_leaves_by(k, |node| {
+            let (min, max) = node.world_bounds();
+            aabb_distance_squared(min, max, point).sqrt()
+        })
+    }
+
+    pub fn nearest_leaf_world(&self, point: [f32; 3]) -> Option<NodeKey> {
+        self.nearest_leaves_world(point, 1).first().map(|(node_key, _)| *node_key)
+    }
+
+    /// Same as `insert_in_aabb_and_update_neighbors`, but the box is given in world space.
+    pub fn insert_in_aabb_world_and_update_neighbors(
+        &mut self,
+        min: [f32; 3],
+        max: [f32; 3],
+        f: impl Fn(&PlanetTreeNode) -> bool,
+    ) -> Vec<TreeEvent<2, Direction>> {
+        self.insert_in_region_and_update_neighbors(
+            |node| {
+                let (node_min, node_max) = node.world_bounds();
+                aabb_intersects_aabb(node_min, node_max, min, max)
+            },
+            f,
+        )
+    }
+
+    /// Same as `insert_in_sphere_and_update_neighbors`, but the sphere is given in world space.
+    pub fn insert_in_sphere_world_and_update_neighbors(
+        &mut self,
+        center: [f32; 3],
+        radius: f32,
+        f: impl Fn(&PlanetTreeNode) -> bool,
+    ) -> Vec<TreeEvent<2, Direction>> {
+        self.insert_in_region_and_update_neighbors(
+            |node| {
+                let (node_min, node_max) = node.world_bounds();
+                aabb_distance_squared(node_min, node_max, center) <= radius * radius
+            },
+            f,
+        )
+    }
+
+    /// Same as `query_sphere`, but the sphere is given in world space.
+    pub fn query_sphere_world(&self, center: [f32; 3], radius: f32, include_covered: bool) -> Vec<NodeKey> {
+        self.query(
+            |node| {
+                let (node_min, node_max) = node.world_bounds();
+                aabb_overlap_sphere(node_min, node_max, center, radius)
+            },
+            include_covered,
+        )
+    }
+}
+
+impl RefineContext<'_, PlanetTreeNode, 2> {
+    /// The cube face the node belongs to.
+    pub fn direction(&self) -> Direction {
+        self.node.direction()
+    }
 }
 
 impl NodeStorage for PlanetTree {
@@ -78,82 +237,111 @@ impl NodeStorage for PlanetTree {
 }
 
 impl TreeBehaviour<2> for PlanetTree {
+    type Face = Direction;
+
     fn min_size(&self) -> f32 {
         self.min_size
     }
 
+    fn min_size_for(&self, node: &PlanetTreeNode) -> f32 {
+        match &self.min_size_fn {
+            Some(f) => f(node).max(self.min_size),
+            None => self.min_size,
+        }
+    }
+
+    fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    fn observer_mut(&mut self) -> Option<&mut BoxedObserver<2, Direction>> {
+        self.observer.as_mut()
+    }
+
+    fn pins(&self) -> Option<&Pins> {
+        Some(&self.pins)
+    }
+
+    fn pins_mut(&mut self) -> Option<&mut Pins> {
+        Some(&mut self.pins)
+    }
+
     fn root_items(&self) -> Vec<NodeKey> {
         self.roots.to_vec()
     }
 
-    fn create_children(&mut self, parent_key: NodeKey) -> Vec<NodeKey> {
-        let (parent_size, parent_pos, parent_direction, parent_world_position) = {
-            let parent = self.get_node_unchecked(parent_key);
-            (
-                parent.size(),
-                parent.pos(),
-                parent.direction(),
-                parent.world_position(),
-            )
-        };
+    fn child_node(&self, parent: &PlanetTreeNode, child_index: usize) -> PlanetTreeNode {
+        let quart_size = parent.size() / 4.0;
+        let pos = child_position::<2>(child_index);
+        let mut child_pos = parent.pos();
+        child_pos.iter_mut().zip(pos.iter()).for_each(|(out, p)| {
+            let v = *out + *p as f32 * quart_size;
+            *out = v;
+        });
 
-        let new_size = parent_size / 2.0;
-        let quart_size = parent_size / 4.0;
-
-        let mut new_child_indexes = vec![];
-        let num_children = 2usize.pow(2);
-
-        for child_index in 0..num_children {
-            let pos = child_position::<2>(child_index);
-            let mut child_pos = parent_pos;
-            child_pos.iter_mut().zip(pos.iter()).for_each(|(out, p)| {
-                let v = *out + *p as f32 * quart_size;
-                *out = v;
-            });
-
-            let mut child = PlanetTreeNode::new(
-                new_size,
-                child_pos,
-                map_from_dir_and_local_pos(parent_direction, child_pos, parent_world_position),
-                parent_direction,
-            );
-
-            child.set_parent(parent_key);
-            new_child_indexes.push(self.insert_node(child));
-        }
-        self.get_mut_node_unchecked(parent_key)
-            .set_child_keys(new_child_indexes.as_slice());
-        new_child_indexes
+        PlanetTreeNode::new(
+            parent.size() / 2.0,
+            child_pos,
+            map_from_dir_and_local_pos(parent.direction(), child_pos, parent.world_position()),
+            parent.direction(),
+        )
     }
 
     fn grow_event(
         &self,
-        events: &mut Vec<TreeEvent>,
+        events: &mut Vec<TreeEvent<2, Direction>>,
         pos: [f32; 2],
         parent_key: NodeKey,
         new_children: &[NodeKey],
     ) {
         let parent_direction = self.get_node_unchecked(parent_key).direction();
+        let new_children = self.child_snapshots(parent_key, new_children);
         for event in events.iter_mut().rev() {
             if let TreeEvent::Grown { parent, children } = event {
                 let current_parrent_node = self.get_node_unchecked(*parent);
                 if current_parrent_node.direction() == parent_direction
                     && current_parrent_node.contains_point(pos)
                 {
-                    children.retain(|node_key| !self.get_node_unchecked(*node_key).has_children());
-                    children.extend(new_children.iter());
+                    children.retain(|child| !self.get_node_unchecked(child.node_key).has_children());
+                    children.extend(new_children);
                     return;
                 }
             }
         }
         events.push(TreeEvent::Grown {
             parent: parent_key,
-            children: Vec::from_iter(new_children.iter().copied()),
+            children: new_children,
         });
     }
+
+    fn face(&self, node: &PlanetTreeNode) -> Direction {
+        node.direction()
+    }
 }
 
 impl TreeNeighbourBehaviour<2> for PlanetTree {
+    fn get_neighbors(&self, node_key: NodeKey, direction: [i32; 2]) -> Vec<NodeKey> {
+        let (mut node, neighbor_descents) = match self.find_shared_parent(node_key, direction) {
+            Some((node, descent)) => (node, descent),
+            None => return vec![],
+        };
+
+        node = self.neighbour_descent(node, neighbor_descents);
+        if !self.get_node_unchecked(node).has_children() {
+            return vec![node];
+        }
+
+        // The neighbor may lie on another face, so the border direction has to be given in that face's frame.
+        let node_dir = self.get_node_unchecked(node_key).direction();
+        let neighbour_dir = self.get_node_unchecked(node).direction();
+        let mut opposite_dir = direction;
+        opposite_dir.iter_mut().for_each(|e| *e *= -1);
+        let mut neighbour_direction = map_from_dir_to_dir(node_dir, neighbour_dir, opposite_dir);
+        neighbour_direction.iter_mut().for_each(|e| *e *= -1);
+
+        self.bordering_neighbours(node, neighbour_direction)
+    }
+
     fn find_shared_parent(
         &self,
         mut node_key: NodeKey,
@@ -220,7 +408,7 @@ impl TreeNeighbourBehaviour<2> for PlanetTree {
     fn update_neighbor_sizes(
         &mut self,
         node_key: NodeKey,
-        visited_nodes: &mut HashMap<NodeKey, NeighborSizeEvent>,
+        visited_nodes: &mut VisitedNodes<2>,
     ) {
         let mut neighbor_sizes = vec![];
         let (node_size, node_dir) = {
@@ -238,13 +426,12 @@ impl TreeNeighbourBehaviour<2> for PlanetTree {
                     (node.size(), node.direction())
                 };
 
-                let neighbour_opposite_dir = map_from_dir_to_dir(node_dir, neighbour_dir, opposite_dir);                                
+                let neighbour_opposite_dir = map_from_dir_to_dir(node_dir, neighbour_dir, opposite_dir);
+                visited_nodes.remember_sides(neighbour_key, self.get_node_unchecked(neighbour_key));
                 if self.update_neighbor_size(node_key, neighbour_key, node_size, neighbour_opposite_dir)
                     == NeighborSizeEvent::ChangedSize
                 {
-                    visited_nodes
-                        .entry(neighbour_key)
-                        .or_insert(NeighborSizeEvent::ChangedSize);
+                    visited_nodes.mark_changed(neighbour_key);
                 }
 
                 if neighbour_size < node_size {
@@ -265,7 +452,7 @@ impl TreeNeighbourBehaviour<2> for PlanetTree {
                 child_node.neighbor_offsets_mut()[offset_index] = *offset;
             }            
         }
-        visited_nodes.insert(node_key, NeighborSizeEvent::New);
+        visited_nodes.mark_updated(node_key);
     }
 
     fn get_neighbor_offsets(&self, node_key: NodeKey, neighbour_key: NodeKey, direction: [i32; 2]) -> Vec<f32> {
@@ -287,6 +474,7 @@ impl TreeNeighbourBehaviour<2> for PlanetTree {
 
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Direction {
     XNeg = 0,
     XPos = 1,
@@ -5,6 +5,7 @@ use crate::{
 };
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PlanetTreeNode {
     size: f32,
     pos: [f32; 2],
@@ -14,6 +15,7 @@ pub struct PlanetTreeNode {
     world_pos: [f32; 3],
     parent: Option<NodeKey>,
     children: Option<[NodeKey; 4]>,
+    refine_state: RefineState,
 }
 
 impl PlanetTreeNode {
@@ -27,6 +29,7 @@ impl PlanetTreeNode {
             world_pos,
             parent: None,
             children: None,
+            refine_state: RefineState::default(),
         }
     }
 
@@ -34,6 +37,22 @@ impl PlanetTreeNode {
         self.world_pos
     }
 
+    /// Bounds of the patch in world space. The box is flat along the face normal.
+    pub fn world_bounds(&self) -> ([f32; 3], [f32; 3]) {
+        let half_size = self.size / 2.0;
+        let normal_axis = self.direction as usize / 2;
+
+        let mut min = self.world_pos;
+        let mut max = self.world_pos;
+        for i in 0..3 {
+            if i != normal_axis {
+                min[i] -= half_size;
+                max[i] += half_size;
+            }
+        }
+        (min, max)
+    }
+
     pub fn set_direction(&mut self, facing: Direction) {
         self.direction = facing
     }
@@ -54,6 +73,7 @@ impl Boundary<2> for PlanetTreeNode {
             world_pos: [0.0, 0.0, 0.0],
             parent: None,
             children: None,
+            refine_state: RefineState::default(),
         }
     }
 
@@ -89,7 +109,7 @@ impl ChildBehaviour<2> for PlanetTreeNode {
 
     fn take_children(&mut self) -> Vec<NodeKey> {
         if let Some(children) = self.children.take() {
-            Vec::from_iter(children.into_iter())
+            Vec::from_iter(children)
         } else {
             vec![]
         }
@@ -110,3 +130,12 @@ impl NeighborBehaviour<2> for PlanetTreeNode {
         self.neighbor_offsets.as_slice()
     }
 }
+
+impl RefineStateBehaviour for PlanetTreeNode {
+    fn refine_state(&self) -> &RefineState {
+        &self.refine_state
+    }
+    fn refine_state_mut(&mut self) -> &mut RefineState {
+        &mut self.refine_state
+    }
+}
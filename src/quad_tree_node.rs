@@ -1,6 +1,7 @@
 use crate::{node_traits::*, NodeKey};
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct QuadTreeNode {
     pub size: f32,
     pub pos: [f32; 2],
@@ -8,6 +9,7 @@ pub struct QuadTreeNode {
     pub neighbor_offsets: [f32; 4],
     pub parent: Option<NodeKey>,
     pub children: Option<[NodeKey; 4]>,
+    pub refine_state: RefineState,
 }
 
 impl Boundary<2> for QuadTreeNode {
@@ -19,6 +21,7 @@ impl Boundary<2> for QuadTreeNode {
             neighbor_offsets: [0.0; 4],
             parent: None,
             children: None,
+            refine_state: RefineState::default(),
         }
     }
 
@@ -54,7 +57,7 @@ impl ChildBehaviour<2> for QuadTreeNode {
 
     fn take_children(&mut self) -> Vec<NodeKey> {
         if let Some(children) = self.children.take() {
-            Vec::from_iter(children.into_iter())
+            Vec::from_iter(children)
         } else {
             vec![]
         }
@@ -75,3 +78,12 @@ impl NeighborBehaviour<2> for QuadTreeNode {
         self.neighbor_offsets.as_slice()
     }
 }
+
+impl RefineStateBehaviour for QuadTreeNode {
+    fn refine_state(&self) -> &RefineState {
+        &self.refine_state
+    }
+    fn refine_state_mut(&mut self) -> &mut RefineState {
+        &mut self.refine_state
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::BinaryHeap;
+
+use crate::{node_traits::*, ntree::NTree, NodeKey};
+
+/// A leaf crossed by a ray, with the ray parameters where it enters and leaves the node.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RayHit {
+    pub node_key: NodeKey,
+    pub t_enter: f32,
+    pub t_exit: f32,
+}
+
+impl<T, const D: usize> NTree<T, D>
+where
+    T: ChildBehaviour<D> + NeighborBehaviour<D> + Boundary<D>,
+{
+    /// Walks the leaves crossed by the ray `origin + t * dir` for `t` in `[0, max_t]`, front to back.
+    /// Leaves the ray only touches at a corner or edge are skipped. A ray running exactly along a border between
+    /// leaves crosses the leaves on both sides, ties in entry distance are visited in key order.
+    /// Stops at, and returns, the first hit `f` returns true for.
+    pub fn raycast(
+        &self,
+        origin: [f32; D],
+        dir: [f32; D],
+        max_t: f32,
+        mut f: impl FnMut(&RayHit) -> bool,
+    ) -> Option<RayHit> {
+        let (min, max) = self.nodes[self.root()].bounds();
+        // Ordered by entry distance over the whole walk, children never start before their parent
+        let mut pending_hits = BinaryHeap::new();
+        if let Some((t_enter, t_exit)) = ray_aabb_intersection(origin, dir, max_t, min, max) {
+            pending_hits.push(PendingHit(RayHit {
+                node_key: self.root(),
+                t_enter,
+                t_exit,
+            }));
+        }
+
+        while let Some(PendingHit(hit)) = pending_hits.pop() {
+            if let Some(children) = self.nodes[hit.node_key].children() {
+                for child_key in children {
+                    let (min, max) = self.nodes[*child_key].bounds();
+                    if let Some((t_enter, t_exit)) = ray_aabb_intersection(origin, dir, max_t, min, max) {
+                        pending_hits.push(PendingHit(RayHit {
+                            node_key: *child_key,
+                            t_enter,
+                            t_exit,
+                        }));
+                    }
+                }
+            } else if f(&hit) {
+                return Some(hit);
+            }
+        }
+
+        None
+    }
+
+    /// Returns every leaf crossed by the ray, ordered by entry distance.
+    pub fn raycast_all(&self, origin: [f32; D], dir: [f32; D], max_t: f32) -> Vec<RayHit> {
+        let mut hits = vec![];
+        self.raycast(origin, dir, max_t, |hit| {
+            hits.push(*hit);
+            false
+        });
+        hits
+    }
+}
+
+/// Heap entry that pops the closest hit first.
+struct PendingHit(RayHit);
+
+impl PartialEq for PendingHit {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for PendingHit {}
+
+impl PartialOrd for PendingHit {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PendingHit {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other
+            .0
+            .t_enter
+            .total_cmp(&self.0.t_enter)
+            .then_with(|| other.0.node_key.cmp(&self.0.node_key))
+    }
+}
+
+/// Slab test between a ray and a box, clipped to `[0, max_t]`.
+/// Only returns intervals of positive length, a ray that just touches the box misses it.
+pub fn ray_aabb_intersection<const D: usize>(
+    origin: [f32; D],
+    dir: [f32; D],
+    max_t: f32,
+    min: [f32; D],
+    max: [f32; D],
+) -> Option<(f32, f32)> {
+    let mut t_enter = 0.0f32;
+    let mut t_exit = max_t;
+    for i in 0..D {
+        if dir[i] == 0.0 {
+            if origin[i] < min[i] || origin[i] > max[i] {
+                return None;
+            }
+        } else {
+            let inv_dir = 1.0 / dir[i];
+            let t0 = (min[i] - origin[i]) * inv_dir;
+            let t1 = (max[i] - origin[i]) * inv_dir;
+            t_enter = t_enter.max(t0.min(t1));
+            t_exit = t_exit.min(t0.max(t1));
+        }
+    }
+
+    if t_enter < t_exit {
+        Some((t_enter, t_exit))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{quad_tree::QuadTree, tree_traits::*};
+
+    // 16x16 grid of leaves of size 2, borders at even coordinates
+    fn grid() -> QuadTree {
+        let mut tree = QuadTree::new(2.0, 16.0, [0.0, 0.0]);
+        tree.insert(|_| true);
+        tree
+    }
+
+    fn brute_force_hits(tree: &QuadTree, origin: [f32; 2], dir: [f32; 2], max_t: f32) -> Vec<NodeKey> {
+        let mut hits: Vec<_> = tree
+            .iter_leaf_nodes()
+            .filter_map(|(node_key, node)| {
+                let (min, max) = node.bounds();
+                ray_aabb_intersection(origin, dir, max_t, min, max).map(|(t_enter, _)| (t_enter, node_key))
+            })
+            .collect();
+        hits.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+        hits.into_iter().map(|(_, node_key)| node_key).collect()
+    }
+
+    fn assert_front_to_back(hits: &[RayHit]) {
+        for hit in hits {
+            assert!(hit.t_enter < hit.t_exit);
+        }
+        for pair in hits.windows(2) {
+            assert!(pair[0].t_enter <= pair[1].t_enter);
+        }
+    }
+
+    #[test]
+    fn diagonal_ray_skips_corner_touches() {
+        let tree = grid();
+        let hits = tree.raycast_all([-9.0, -9.0], [1.0, 1.0], 100.0);
+        assert_front_to_back(&hits);
+        assert_eq!(hits.len(), 8);
+        for hit in &hits {
+            let pos = tree.nodes[hit.node_key].pos;
+            assert_eq!(pos[0], pos[1]);
+        }
+        let keys: Vec<_> = hits.iter().map(|hit| hit.node_key).collect();
+        assert_eq!(keys, brute_force_hits(&tree, [-9.0, -9.0], [1.0, 1.0], 100.0));
+    }
+
+    #[test]
+    fn ray_along_grid_line_hits_both_sides_in_order() {
+        let tree = grid();
+        for (origin, dir) in [([-9.0, 0.0], [1.0, 0.0]), ([4.0, 9.0], [0.0, -1.0]), ([-8.0, -9.0], [0.0, 1.0])] {
+            let hits = tree.raycast_all(origin, dir, 100.0);
+            assert_front_to_back(&hits);
+            let keys: Vec<_> = hits.iter().map(|hit| hit.node_key).collect();
+            assert_eq!(keys, brute_force_hits(&tree, origin, dir, 100.0));
+        }
+        assert_eq!(tree.raycast_all([-9.0, 0.0], [1.0, 0.0], 100.0).len(), 16);
+        // The border of the root only has leaves on one side
+        assert_eq!(tree.raycast_all([-8.0, -9.0], [0.0, 1.0], 100.0).len(), 8);
+    }
+
+    #[test]
+    fn random_rays_match_brute_force() {
+        let mut tree = QuadTree::new(0.5, 16.0, [0.0, 0.0]);
+        tree.insert(|node| node.pos[0] * node.pos[1] > 0.0);
+        let mut rng = fastrand::Rng::with_seed(3);
+        for _ in 0..50 {
+            let origin = [rng.f32() * 24.0 - 12.0, rng.f32() * 24.0 - 12.0];
+            let dir = [rng.f32() - 0.5, rng.f32() - 0.5];
+            let hits = tree.raycast_all(origin, dir, 40.0);
+            assert_front_to_back(&hits);
+            let keys: Vec<_> = hits.iter().map(|hit| hit.node_key).collect();
+            assert_eq!(keys, brute_force_hits(&tree, origin, dir, 40.0));
+        }
+    }
+
+    #[test]
+    fn stops_at_first_accepted_leaf() {
+        let tree = grid();
+        let mut visited = vec![];
+        let hit = tree
+            .raycast([-9.0, -3.0], [1.0, 0.0], 100.0, |hit| {
+                visited.push(hit.node_key);
+                tree.nodes[hit.node_key].pos[0] > 0.0
+            })
+            .unwrap();
+        assert_eq!(tree.nodes[hit.node_key].pos, [1.0, -3.0]);
+        assert_eq!(visited.len(), 5);
+        assert_eq!(visited.last(), Some(&hit.node_key));
+
+        assert!(tree.raycast([-9.0, -3.0], [1.0, 0.0], 4.0, |_| false).is_none());
+    }
+}
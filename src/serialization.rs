@@ -0,0 +1,188 @@
+use crate::{node_traits::ChildBehaviour, pins::Pins, NodeKey};
+use serde::{Deserialize, Serialize};
+use slotmap::{SecondaryMap, SlotMap};
+
+/// A tree saved together with optional user data for its nodes.
+///
+/// The node slotmap is serialized with its slot versions, so every `NodeKey` in the loaded tree is the same key
+/// as before saving. User data in a `SecondaryMap` therefore stays attached to the right nodes without remapping.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TreeWithData<Tree, V> {
+    pub tree: Tree,
+    pub data: Option<SecondaryMap<NodeKey, V>>,
+}
+
+impl<Tree, V> TreeWithData<Tree, V> {
+    pub fn new(tree: Tree, data: Option<SecondaryMap<NodeKey, V>>) -> Self {
+        Self { tree, data }
+    }
+}
+
+/// Checks the links of a loaded tree: every root exists and has no parent, every child exists and points back to
+/// its parent, every node is reached exactly once from the roots, and every pinned node exists.
+/// The trees look nodes up with `get_node_unchecked`, so a corrupted file would otherwise panic long after loading.
+pub(crate) fn check_links<T, const D: usize>(
+    nodes: &SlotMap<NodeKey, T>,
+    roots: &[NodeKey],
+    pins: &Pins,
+) -> Result<(), String>
+where
+    T: ChildBehaviour<D>,
+{
+    let mut pending_node_keys = vec![];
+    for &root in roots {
+        match nodes.get(root) {
+            None => return Err(format!("root {:?} is not in the tree", root)),
+            Some(node) if node.get_parent().is_some() => return Err(format!("root {:?} has a parent", root)),
+            Some(_) => pending_node_keys.push(root),
+        }
+    }
+
+    let mut visited = SecondaryMap::new();
+    while let Some(node_key) = pending_node_keys.pop() {
+        if visited.insert(node_key, ()).is_some() {
+            return Err(format!("node {:?} is reached twice from the roots", node_key));
+        }
+        for &child_key in nodes[node_key].children().unwrap_or_default() {
+            match nodes.get(child_key) {
+                None => return Err(format!("child {:?} of node {:?} is not in the tree", child_key, node_key)),
+                Some(child) if child.get_parent() != Some(node_key) => {
+                    return Err(format!("child {:?} of node {:?} has another parent", child_key, node_key))
+                }
+                Some(_) => pending_node_keys.push(child_key),
+            }
+        }
+    }
+    if let Some(node_key) = nodes.keys().find(|node_key| !visited.contains_key(*node_key)) {
+        return Err(format!("node {:?} is not reached from the roots", node_key));
+    }
+
+    match pins.node_keys().find(|node_key| !nodes.contains_key(*node_key)) {
+        Some(node_key) => Err(format!("pinned node {:?} is not in the tree", node_key)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{oct_tree::*, planet_tree::PlanetTree, quad_tree::*, test_utils::as_text};
+
+    #[test]
+    fn quad_tree_round_trip() {
+        let mut tree = QuadTree::new(1.0, 64.0, [0.0, 0.0]).with_max_depth(5);
+        tree.insert_and_update_neighbors(|node| node.pos[0] < 10.0 && node.size > 2.0);
+
+        let text = serde_json::to_string(&tree).unwrap();
+        let loaded: QuadTree = serde_json::from_str(&text).unwrap();
+        assert_eq!(as_text(tree.nodes.iter()), as_text(loaded.nodes.iter()));
+        assert_eq!(tree.root(), loaded.root());
+        assert_eq!(tree.min_size, loaded.min_size);
+        assert_eq!(tree.max_depth, loaded.max_depth);
+    }
+
+    #[test]
+    fn oct_tree_round_trip() {
+        let mut tree = OctTree::new(1.0, 32.0, [0.0, 0.0, 0.0]);
+        tree.insert_and_update_neighbors(|node| node.pos[2] > 0.0 && node.size > 4.0);
+
+        let text = serde_json::to_string(&tree).unwrap();
+        let loaded: OctTree = serde_json::from_str(&text).unwrap();
+        assert_eq!(as_text(tree.nodes.iter()), as_text(loaded.nodes.iter()));
+        assert_eq!(tree.root(), loaded.root());
+    }
+
+    #[test]
+    fn planet_tree_round_trip() {
+        let mut tree = PlanetTree::new(1.0, 64.0, [0.0, 0.0, 0.0]);
+        tree.insert_in_sphere_world_and_update_neighbors([32.0, 0.0, 0.0], 20.0, |node| node.size() > 4.0);
+
+        let text = serde_json::to_string(&tree).unwrap();
+        let loaded: PlanetTree = serde_json::from_str(&text).unwrap();
+        assert_eq!(as_text(tree.nodes.iter()), as_text(loaded.nodes.iter()));
+        assert_eq!(tree.root_items(), loaded.root_items());
+        assert_eq!(tree.min_size, loaded.min_size);
+    }
+
+    #[test]
+    fn tree_with_data_round_trip() {
+        let mut tree = QuadTree::new(1.0, 16.0, [0.0, 0.0]);
+        tree.insert_and_update_neighbors(|node| node.size > 4.0);
+        let mut data = slotmap::SecondaryMap::new();
+        for (node_key, node) in tree.iter_leaf_nodes() {
+            data.insert(node_key, node.pos);
+        }
+
+        let saved = TreeWithData::new(tree, Some(data));
+        let text = serde_json::to_string(&saved).unwrap();
+        let loaded: TreeWithData<QuadTree, [f32; 2]> = serde_json::from_str(&text).unwrap();
+        let data = loaded.data.unwrap();
+        assert_eq!(data.len(), 16);
+        for (node_key, node) in loaded.tree.iter_leaf_nodes() {
+            assert_eq!(data[node_key], node.pos);
+        }
+    }
+
+    #[test]
+    fn rejects_broken_links() {
+        let mut tree = QuadTree::new(1.0, 4.0, [0.0, 0.0]);
+        tree.insert(|node| node.size > 2.0);
+        let saved = serde_json::to_value(&tree).unwrap();
+        // Loads an edited copy of the tree and returns the error, if any
+        let load = |edit: &dyn Fn(&mut serde_json::Value)| {
+            let mut value = saved.clone();
+            edit(&mut value);
+            serde_json::from_value::<QuadTree>(value).err().map(|err| err.to_string())
+        };
+        let missing_key = serde_json::json!({ "idx": 9, "version": 1 });
+        let first_child = saved["nodes"][1]["value"]["children"][0].clone();
+
+        assert_eq!(load(&|_| {}), None);
+        assert!(load(&|value| value["root"] = missing_key.clone()).unwrap().contains("root"));
+        let error = load(&|value| value["nodes"][2]["value"]["parent"] = serde_json::Value::Null);
+        assert!(error.unwrap().contains("another parent"));
+        let error = load(&|value| value["nodes"][2]["value"]["parent"] = missing_key.clone());
+        assert!(error.unwrap().contains("another parent"));
+        let error = load(&|value| value["nodes"][1]["value"]["children"][0] = missing_key.clone());
+        assert!(error.unwrap().contains("not in the tree"));
+        let error = load(&|value| value["nodes"][1]["value"]["children"][1] = first_child.clone());
+        assert!(error.unwrap().contains("reached twice"));
+        let error = load(&|value| value["nodes"][1]["value"]["children"] = serde_json::Value::Null);
+        assert!(error.unwrap().contains("not reached"));
+        let error = load(&|value| value["pins"]["counts"] = serde_json::json!([[missing_key.clone(), 1]]));
+        assert!(error.unwrap().contains("pinned"));
+
+        let mut text = serde_json::to_string(&TreeWithData::<_, ()>::new(tree, None)).unwrap();
+        text = text.replacen(r#""root":{"idx":1"#, r#""root":{"idx":9"#, 1);
+        assert!(serde_json::from_str::<TreeWithData<QuadTree, ()>>(&text).is_err());
+
+        let planet_tree = PlanetTree::new(1.0, 64.0, [0.0, 0.0, 0.0]);
+        let mut value = serde_json::to_value(&planet_tree).unwrap();
+        value["roots"][3] = value["roots"][2].clone();
+        assert!(serde_json::from_value::<PlanetTree>(value).is_err());
+    }
+
+    #[test]
+    fn pins_round_trip() {
+        let mut tree = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        tree.insert_and_update_neighbors(|node| node.size > 8.0);
+        let pinned = tree.pin_region([1.0, 1.0], [2.0, 2.0]);
+        tree.pin(pinned[0]);
+
+        let text = serde_json::to_string(&tree).unwrap();
+        let mut loaded: QuadTree = serde_json::from_str(&text).unwrap();
+        assert_eq!(loaded.pins().unwrap().count(pinned[0]), 2);
+        loaded.insert_and_update_neighbors(|_| false);
+        assert!(loaded.get_node(pinned[0]).is_some());
+
+        let mut planet_tree = PlanetTree::new(1.0, 64.0, [0.0, 0.0, 0.0]);
+        planet_tree.insert(|node| node.size() > 16.0);
+        let pinned = planet_tree.leaf_keys()[0];
+        planet_tree.pin(pinned);
+
+        let text = serde_json::to_string(&planet_tree).unwrap();
+        let mut loaded: PlanetTree = serde_json::from_str(&text).unwrap();
+        loaded.insert(|_| false);
+        assert!(loaded.get_node(pinned).is_some());
+    }
+}
@@ -0,0 +1,38 @@
+use crate::{node_traits::*, tree_traits::*};
+
+/// Debug text of every value, for comparing trees and events that do not implement `PartialEq`.
+pub fn as_text<T: std::fmt::Debug>(values: impl IntoIterator<Item = T>) -> Vec<String> {
+    values.into_iter().map(|v| format!("{v:?}")).collect()
+}
+
+/// Position and size of a node, which identify it across trees built in a different order.
+pub fn node_id<const D: usize>(node: &impl Boundary<D>) -> String {
+    format!("{:?} {}", node.pos(), node.size())
+}
+
+/// Neighbor sizes and offsets of every leaf, keyed by `node_id`.
+pub fn leaf_sides<Tree, const D: usize>(tree: &Tree) -> Vec<String>
+where
+    Tree: TreeBehaviour<D>,
+    Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+{
+    leaf_sides_by(tree, node_id)
+}
+
+/// Like `leaf_sides`, with the leaves keyed by `id`.
+pub fn leaf_sides_by<Tree, const D: usize>(tree: &Tree, id: impl Fn(&Tree::NodeType) -> String) -> Vec<String>
+where
+    Tree: TreeBehaviour<D>,
+    Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+{
+    let mut sides: Vec<_> = tree
+        .leaf_keys()
+        .into_iter()
+        .map(|node_key| {
+            let node = tree.get_node_unchecked(node_key);
+            format!("{} {:?} {:?}", id(node), node.neighbor_sizes(), node.neighbor_offsets())
+        })
+        .collect();
+    sides.sort();
+    sides
+}
@@ -0,0 +1,319 @@
+use std::collections::VecDeque;
+
+use crate::{node_traits::*, ntree::NTree, planet_tree_impl::PlanetTree, tree_traits::*, NodeKey};
+
+/// Error returned when a topology stream cannot be decoded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TopologyError {
+    /// The stream ended before the header or the last node flag.
+    UnexpectedEnd,
+    /// The header has a non-finite position, or a size or `min_size` that is not a positive number.
+    InvalidHeader,
+    /// The stream has bytes left after the last node flag.
+    TrailingBytes,
+    /// A node is split below `min_size`.
+    InvalidSplit,
+}
+
+impl std::fmt::Display for TopologyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TopologyError::UnexpectedEnd => write!(f, "topology stream ended unexpectedly"),
+            TopologyError::InvalidHeader => write!(f, "topology stream has an invalid header"),
+            TopologyError::TrailingBytes => write!(f, "topology stream has trailing bytes"),
+            TopologyError::InvalidSplit => write!(f, "topology stream splits a node below the minimum size"),
+        }
+    }
+}
+
+impl std::error::Error for TopologyError {}
+
+impl<T, const D: usize> NTree<T, D>
+where
+    T: ChildBehaviour<D> + NeighborBehaviour<D> + Boundary<D> + std::fmt::Debug,
+{
+    /// Encodes the tree structure as the root position, root size and `min_size`,
+    /// followed by one "has children" bit per node in breadth-first order.
+    pub fn encode_topology(&self) -> Vec<u8> {
+        let root = &self.nodes[self.root()];
+        let mut writer = BitWriter::default();
+        for v in root.pos() {
+            writer.write_f32(v);
+        }
+        writer.write_f32(root.size());
+        writer.write_f32(self.min_size);
+        encode_children(self, &mut writer);
+        writer.bytes
+    }
+
+    /// Rebuilds a tree written by `encode_topology`. Also returns the new node keys in stream order,
+    /// so data stored in the same order can be attached to the new nodes.
+    pub fn decode_topology(bytes: &[u8]) -> Result<(Self, Vec<NodeKey>), TopologyError> {
+        let mut reader = BitReader::new(bytes);
+        let (pos, size, min_size) = read_header(&mut reader)?;
+
+        let mut tree = Self::new(min_size, size, pos);
+        let node_keys = decode_children(&mut tree, &mut reader)?;
+        reader.finish()?;
+        Ok((tree, node_keys))
+    }
+}
+
+impl PlanetTree {
+    /// Encodes the tree structure as the planet center, face size and `min_size`,
+    /// followed by one "has children" bit per node in breadth-first order, starting with the six faces.
+    pub fn encode_topology(&self) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        for v in self.center() {
+            writer.write_f32(v);
+        }
+        writer.write_f32(self.size());
+        writer.write_f32(self.min_size);
+        encode_children(self, &mut writer);
+        writer.bytes
+    }
+
+    /// Rebuilds a tree written by `encode_topology`. Also returns the new node keys in stream order,
+    /// so data stored in the same order can be attached to the new nodes.
+    pub fn decode_topology(bytes: &[u8]) -> Result<(Self, Vec<NodeKey>), TopologyError> {
+        let mut reader = BitReader::new(bytes);
+        let (pos, size, min_size) = read_header(&mut reader)?;
+
+        let mut tree = Self::new(min_size, size, pos);
+        let node_keys = decode_children(&mut tree, &mut reader)?;
+        reader.finish()?;
+        Ok((tree, node_keys))
+    }
+}
+
+fn read_header<const D: usize>(reader: &mut BitReader) -> Result<([f32; D], f32, f32), TopologyError> {
+    let mut pos = [0.0; D];
+    for v in pos.iter_mut() {
+        *v = reader.read_f32()?;
+    }
+    let size = reader.read_f32()?;
+    let min_size = reader.read_f32()?;
+
+    let valid_size = |v: f32| v.is_finite() && v > 0.0;
+    if !pos.iter().all(|v| v.is_finite()) || !valid_size(size) || !valid_size(min_size) {
+        return Err(TopologyError::InvalidHeader);
+    }
+    Ok((pos, size, min_size))
+}
+
+fn encode_children<Tree, const D: usize>(tree: &Tree, writer: &mut BitWriter)
+where
+    Tree: TreeBehaviour<D>,
+    Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D>,
+{
+    let mut pending_node_keys = VecDeque::from(tree.root_items());
+    while let Some(node_key) = pending_node_keys.pop_front() {
+        match tree.get_node_unchecked(node_key).children() {
+            Some(children) => {
+                writer.write_bit(true);
+                pending_node_keys.extend(children.iter());
+            }
+            None => writer.write_bit(false),
+        }
+    }
+}
+
+fn decode_children<Tree, const D: usize>(
+    tree: &mut Tree,
+    reader: &mut BitReader,
+) -> Result<Vec<NodeKey>, TopologyError>
+where
+    Tree: TreeNeighbourBehaviour<D>,
+    Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+{
+    let mut node_keys = vec![];
+    let mut events = vec![];
+    let mut pending_node_keys: VecDeque<(NodeKey, usize)> =
+        tree.root_items().into_iter().map(|node_key| (node_key, 0)).collect();
+    while let Some((node_key, depth)) = pending_node_keys.pop_front() {
+        node_keys.push(node_key);
+        if reader.read_bit()? {
+            let node = tree.get_node_unchecked(node_key);
+            // Also bounds the depth of the tree, whatever the stream says
+            if !tree.can_split_node(node, depth) {
+                return Err(TopologyError::InvalidSplit);
+            }
+            let parent_pos = node.pos();
+            let new_children = tree.create_children(node_key);
+            tree.grow_event(&mut events, parent_pos, node_key, &new_children);
+            pending_node_keys.extend(new_children.into_iter().map(|child_key| (child_key, depth + 1)));
+        }
+    }
+    tree.update_neighbors_from_events(&mut events);
+    Ok(node_keys)
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit: usize,
+}
+
+impl BitWriter {
+    fn write_f32(&mut self, v: f32) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+        self.bit = 0;
+    }
+
+    fn write_bit(&mut self, v: bool) {
+        if self.bit == 0 {
+            self.bytes.push(0);
+        }
+        if v {
+            *self.bytes.last_mut().unwrap() |= 1 << self.bit;
+        }
+        self.bit = (self.bit + 1) % 8;
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    index: usize,
+    bit: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            index: 0,
+            bit: 0,
+        }
+    }
+
+    fn read_f32(&mut self) -> Result<f32, TopologyError> {
+        let bytes = self
+            .bytes
+            .get(self.index..self.index + 4)
+            .ok_or(TopologyError::UnexpectedEnd)?;
+        self.index += 4;
+        Ok(f32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_bit(&mut self) -> Result<bool, TopologyError> {
+        let byte = self.bytes.get(self.index).ok_or(TopologyError::UnexpectedEnd)?;
+        let v = byte & (1 << self.bit) != 0;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.index += 1;
+        }
+        Ok(v)
+    }
+
+    /// Checks that nothing but the unused bits of the last byte is left.
+    fn finish(&self) -> Result<(), TopologyError> {
+        let used_bytes = if self.bit == 0 { self.index } else { self.index + 1 };
+        if used_bytes == self.bytes.len() {
+            Ok(())
+        } else {
+            Err(TopologyError::TrailingBytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{planet_tree::PlanetTree, quad_tree::QuadTree};
+
+    // Node bounds and neighbor sizes in breadth-first order, which is also the stream order
+    fn shape<Tree, const D: usize>(tree: &Tree) -> Vec<String>
+    where
+        Tree: TreeBehaviour<D>,
+        Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D>,
+    {
+        let mut pending_node_keys = VecDeque::from(tree.root_items());
+        let mut shape = vec![];
+        while let Some(node_key) = pending_node_keys.pop_front() {
+            let node = tree.get_node_unchecked(node_key);
+            shape.push(format!("{:?} {} {:?}", node.pos(), node.size(), node.neighbor_sizes()));
+            if let Some(children) = node.children() {
+                pending_node_keys.extend(children.iter());
+            }
+        }
+        shape
+    }
+
+    fn quad_tree() -> QuadTree {
+        let mut tree = QuadTree::new(1.0, 64.0, [4.0, -2.0]);
+        tree.insert_and_update_neighbors(|node| node.contains_point([10.0, 10.0]));
+        tree
+    }
+
+    #[test]
+    fn quad_tree_round_trip() {
+        let tree = quad_tree();
+        let (decoded, node_keys) = QuadTree::decode_topology(&tree.encode_topology()).unwrap();
+        assert_eq!(shape(&tree), shape(&decoded));
+        assert_eq!(node_keys.len(), decoded.nodes.len());
+        assert_eq!(decoded.min_size, tree.min_size);
+    }
+
+    #[test]
+    fn planet_tree_round_trip() {
+        let mut tree = PlanetTree::new(1.0, 64.0, [0.0, 0.0, 0.0]);
+        tree.insert_and_update_neighbors(|node| node.size() > 16.0);
+        let (decoded, node_keys) = PlanetTree::decode_topology(&tree.encode_topology()).unwrap();
+        assert_eq!(shape(&tree), shape(&decoded));
+        assert_eq!(node_keys.len(), decoded.nodes.len());
+    }
+
+    #[test]
+    fn rejects_invalid_header() {
+        let mut bytes = quad_tree().encode_topology();
+        bytes[8..12].copy_from_slice(&f32::NAN.to_le_bytes());
+        assert_eq!(
+            QuadTree::decode_topology(&bytes).err(),
+            Some(TopologyError::InvalidHeader)
+        );
+
+        let mut bytes = quad_tree().encode_topology();
+        bytes[12..16].copy_from_slice(&0.0f32.to_le_bytes());
+        assert_eq!(
+            QuadTree::decode_topology(&bytes).err(),
+            Some(TopologyError::InvalidHeader)
+        );
+    }
+
+    #[test]
+    fn rejects_splits_below_min_size() {
+        // The quad tree is split down to a size of 4
+        let mut bytes = quad_tree().encode_topology();
+        bytes[12..16].copy_from_slice(&8.0f32.to_le_bytes());
+        assert_eq!(
+            QuadTree::decode_topology(&bytes).err(),
+            Some(TopologyError::InvalidSplit)
+        );
+
+        // A stream of nothing but splits
+        let mut bytes = QuadTree::new(16.0, 64.0, [0.0, 0.0]).encode_topology();
+        bytes.pop();
+        bytes.extend([0xff; 64]);
+        assert_eq!(
+            QuadTree::decode_topology(&bytes).err(),
+            Some(TopologyError::InvalidSplit)
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_and_trailing_bytes() {
+        let bytes = quad_tree().encode_topology();
+        assert_eq!(
+            QuadTree::decode_topology(&bytes[..bytes.len() - 1]).err(),
+            Some(TopologyError::UnexpectedEnd)
+        );
+
+        let mut bytes = bytes;
+        bytes.push(0);
+        assert_eq!(
+            QuadTree::decode_topology(&bytes).err(),
+            Some(TopologyError::TrailingBytes)
+        );
+    }
+}
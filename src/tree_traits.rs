@@ -1,6 +1,10 @@
-use crate::{node_traits::*, NodeKey};
+use crate::{leaf_diff::LeafDiff, node_traits::*, observer::BoxedObserver, pins::Pins, NodeKey};
 
-use ahash::AHashMap as HashMap;
+use ahash::{AHashMap as HashMap, AHashSet as HashSet};
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+/// Gives the smallest allowed node size around a node, see `TreeBehaviour::min_size_for`.
+pub type MinSizeFn<N> = Box<dyn Fn(&N) -> f32 + Send + Sync>;
 
 pub trait NodeStorage {
     type NodeType: std::fmt::Debug;
@@ -19,22 +23,287 @@ where
     Self: NodeStorage<NodeKeyType = NodeKey>,
     <Self as NodeStorage>::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
 {
-    fn insert(&mut self, f: impl Fn(&Self::NodeType) -> bool) -> Vec<TreeEvent> {
+    /// Carried by the `NodeSnapshot`s in events, for trees made of several separate parts, e.g. the cube face
+    /// `Direction` of a `PlanetTree` node. `()` for trees with a single root.
+    type Face: Copy + PartialEq + std::fmt::Debug;
+
+    fn insert(&mut self, f: impl Fn(&Self::NodeType) -> bool) -> Vec<TreeEvent<D, Self::Face>> {
+        self.refine(|node| if f(node) { Refine::Split } else { Refine::Merge })
+    }
+
+    /// Refines the tree with a tri-state predicate. `Split` splits leaves and descends into existing children,
+    /// `Keep` leaves the node as it is and lets its children decide for themselves, `Merge` collapses the node's children.
+    fn refine(&mut self, mut f: impl FnMut(&Self::NodeType) -> Refine) -> Vec<TreeEvent<D, Self::Face>> {
+        self.refine_with_context(|context| f(context.node))
+    }
+
+    /// Like `refine`, but the predicate gets a `RefineContext` with the node key, depth, parent bounds and neighbor sizes.
+    fn refine_with_context(
+        &mut self,
+        f: impl FnMut(&RefineContext<Self::NodeType, D>) -> Refine,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        self.refine_region_with_context(|_| true, f)
+    }
+
+    /// Like `insert`, but only revisits existing nodes for which `in_region` is true, e.g. nodes overlapping the union of
+    /// the old and new viewer spheres. Nodes created during the call are always visited.
+    ///
+    /// If the tree was refined with a predicate that only changed its outcome inside the region, the result and the
+    /// events are the same as a full `insert`. `in_region` must be true for the ancestors of every node it is true for.
+    fn insert_in_region(
+        &mut self,
+        in_region: impl Fn(&Self::NodeType) -> bool,
+        f: impl Fn(&Self::NodeType) -> bool,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        self.refine_region_with_context(in_region, |context| {
+            if f(context.node) {
+                Refine::Split
+            } else {
+                Refine::Merge
+            }
+        })
+    }
+
+    /// `insert_in_region` with the nodes overlapping the box as region.
+    fn insert_in_aabb(
+        &mut self,
+        min: [f32; D],
+        max: [f32; D],
+        f: impl Fn(&Self::NodeType) -> bool,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        self.insert_in_region(|node| node.overlap_aabb(min, max) != Overlap::Disjoint, f)
+    }
+
+    /// `insert_in_region` with the nodes overlapping the sphere as region.
+    fn insert_in_sphere(
+        &mut self,
+        center: [f32; D],
+        radius: f32,
+        f: impl Fn(&Self::NodeType) -> bool,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        self.insert_in_region(|node| node.overlap_sphere(center, radius) != Overlap::Disjoint, f)
+    }
+
+    /// `refine_with_context` restricted to a region, see `insert_in_region`.
+    fn refine_region_with_context(
+        &mut self,
+        in_region: impl Fn(&Self::NodeType) -> bool,
+        mut f: impl FnMut(&RefineContext<Self::NodeType, D>) -> Refine,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        let mut events = vec![];
+        // Node key, depth and whether the node was created during this call
+        let mut pending_node_keys: Vec<(NodeKey, usize, bool)> =
+            self.root_items().into_iter().map(|node_key| (node_key, 0, false)).collect();
+        while let Some((node_key, depth, created)) = pending_node_keys.pop() {
+            let node = self.get_node_unchecked(node_key);
+            if !created && !in_region(node) {
+                continue;
+            }
+            let context = RefineContext {
+                node_key,
+                node,
+                depth,
+                parent_bounds: node
+                    .get_parent()
+                    .map(|parent_key| self.get_node_unchecked(parent_key).bounds()),
+                is_leaf: !node.has_children(),
+                neighbor_sizes: node.neighbor_sizes(),
+            };
+            match f(&context) {
+                Refine::Split => {
+                    if let Some(children) = node.children() {
+                        pending_node_keys.extend(children.iter().map(|child_key| (*child_key, depth + 1, false)));
+                    } else if self.can_split_node(node, depth) {
+                        let parent_pos = node.pos();
+                        let new_children = self.create_children(node_key);
+                        self.grow_event(&mut events, parent_pos, node_key, &new_children);
+                        pending_node_keys.extend(new_children.iter().map(|child_key| (*child_key, depth + 1, true)));
+                    }
+                }
+                Refine::Keep => {
+                    if let Some(children) = node.children() {
+                        pending_node_keys.extend(children.iter().map(|child_key| (*child_key, depth + 1, false)));
+                    }
+                }
+                Refine::Merge => {
+                    if !self.is_merge_protected(node_key) {
+                        self.shrink_event(&mut events, node_key);
+                    } else if let Some(children) = node.children() {
+                        pending_node_keys.extend(children.iter().map(|child_key| (*child_key, depth + 1, false)));
+                    }
+                }
+            }
+        }
+        events
+    }
+
+    /// Like `insert`, but the predicate gets a `RefineContext`.
+    fn insert_with_context(
+        &mut self,
+        f: impl Fn(&RefineContext<Self::NodeType, D>) -> bool,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        self.refine_with_context(|context| if f(context) { Refine::Split } else { Refine::Merge })
+    }
+
+    /// Refines the tree with separate split and merge criteria. A node splits if `split` is true,
+    /// merges if `split` is false and `merge` is true, and is kept otherwise.
+    fn refine_with_criteria(
+        &mut self,
+        mut split: impl FnMut(&Self::NodeType) -> bool,
+        mut merge: impl FnMut(&Self::NodeType) -> bool,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        self.refine(|node| {
+            if split(node) {
+                Refine::Split
+            } else if merge(node) {
+                Refine::Merge
+            } else {
+                Refine::Keep
+            }
+        })
+    }
+
+    /// Like `insert`, but a node only merges once it has failed to keep its children for `hysteresis.merge_frames`
+    /// calls in a row. `f` gets a scale factor: 1.0 when deciding whether a leaf splits, and `1.0 + hysteresis.margin`
+    /// when deciding whether a node keeps its children. A predicate that scales its threshold by the factor
+    /// gets a band where nodes neither split nor merge.
+    fn insert_with_hysteresis(
+        &mut self,
+        f: impl Fn(&Self::NodeType, f32) -> bool,
+        hysteresis: Hysteresis,
+    ) -> Vec<TreeEvent<D, Self::Face>>
+    where
+        Self::NodeType: RefineStateBehaviour,
+    {
+        let keep_scale = 1.0 + hysteresis.margin;
+        // Merge frames are counted during the walk and written back once the tree can be changed again
+        let mut merge_frames = vec![];
+        let events = self.refine_with_context(|context| {
+            if context.is_leaf {
+                return if f(context.node, 1.0) { Refine::Split } else { Refine::Keep };
+            }
+            if f(context.node, keep_scale) {
+                merge_frames.push((context.node_key, 0));
+                return Refine::Split;
+            }
+            let frames = context.node.refine_state().merge_frames + 1;
+            merge_frames.push((context.node_key, frames));
+            if frames >= hysteresis.merge_frames {
+                Refine::Merge
+            } else {
+                Refine::Keep
+            }
+        });
+
+        for (node_key, frames) in merge_frames {
+            let node = self.get_mut_node_unchecked(node_key);
+            // Merged nodes start counting again, merge protected nodes keep counting
+            node.refine_state_mut().merge_frames = if node.has_children() { frames } else { 0 };
+        }
+        events
+    }
+
+    /// Refines the tree to the best tree with at most `max_leaves` leaves, ROAM style. `priority` is the error of keeping
+    /// a node as a leaf: the leaves with the highest priority are split first, and the nodes with the lowest priority
+    /// whose children are all leaves are merged first when leaves are needed elsewhere. Leaves with a priority of
+    /// zero or less are never split, and nodes with a priority of zero or less are merged even when within budget.
+    ///
+    /// A node is split or merged at most once per call, so with a priority that changes between calls the tree
+    /// converges over several calls.
+    fn refine_with_budget(&mut self, priority: impl Fn(&Self::NodeType) -> f32, max_leaves: usize) -> Vec<TreeEvent<D, Self::Face>> {
+        let added_leaves = (1usize << D) - 1;
         let mut events = vec![];
+        let mut split_queue = BinaryHeap::new();
+        let mut merge_queue = BinaryHeap::new();
+        let mut leaf_count = 0;
         let mut pending_node_keys = self.root_items();
         while let Some(node_key) = pending_node_keys.pop() {
-            if f(self.get_node_unchecked(node_key)) {
-                let node = &self.get_node_unchecked(node_key);
-                if let Some(children) = node.children() {
+            let node = self.get_node_unchecked(node_key);
+            match node.children() {
+                Some(children) => {
                     pending_node_keys.extend(children.iter());
-                } else if node.size() > self.min_size() {
+                    if children.iter().all(|child_key| !self.get_node_unchecked(*child_key).has_children()) {
+                        merge_queue.push(Reverse(NodePriority::new(priority(node), node_key)));
+                    }
+                }
+                None => {
+                    leaf_count += 1;
+                    split_queue.push(NodePriority::new(priority(node), node_key));
+                }
+            }
+        }
+
+        let mut split_nodes = HashSet::new();
+        let mut merged_nodes = HashSet::new();
+        loop {
+            // Entries are invalidated lazily: skip nodes that were removed or changed shape since they were queued
+            while let Some(Reverse(candidate)) = merge_queue.peek() {
+                let node_key = candidate.node_key;
+                let mergeable = !split_nodes.contains(&node_key)
+                    && !self.is_merge_protected(node_key)
+                    && self.get_node(node_key).and_then(|node| node.children()).is_some_and(|children| {
+                        children.iter().all(|child_key| !self.get_node_unchecked(*child_key).has_children())
+                    });
+                if mergeable {
+                    break;
+                }
+                merge_queue.pop();
+            }
+            while let Some(candidate) = split_queue.peek() {
+                let node_key = candidate.node_key;
+                let splittable = candidate.priority > 0.0
+                    && !merged_nodes.contains(&node_key)
+                    && self.get_node(node_key).is_some_and(|node| !node.has_children())
+                    && self.can_split(node_key);
+                if splittable {
+                    break;
+                }
+                split_queue.pop();
+            }
+
+            let merge_candidate = merge_queue.peek().map(|Reverse(candidate)| *candidate);
+            let split_candidate = split_queue.peek().copied();
+            let merge = match (merge_candidate, split_candidate) {
+                (Some(merge), _) if merge.priority <= 0.0 || leaf_count > max_leaves => Some(merge),
+                (_, Some(split)) if leaf_count + added_leaves <= max_leaves => {
+                    split_queue.pop();
+                    split_nodes.insert(split.node_key);
+                    let node = self.get_node_unchecked(split.node_key);
                     let parent_pos = node.pos();
-                    let new_children = self.create_children(node_key);
-                    self.grow_event(&mut events, parent_pos, node_key, &new_children);
-                    pending_node_keys.extend(new_children.iter());
-                };
-            } else {
-                self.shrink_event(&mut events, node_key);
+                    let new_children = self.create_children(split.node_key);
+                    self.grow_event(&mut events, parent_pos, split.node_key, &new_children);
+                    leaf_count += added_leaves;
+                    for child_key in new_children {
+                        let child_priority = priority(self.get_node_unchecked(child_key));
+                        split_queue.push(NodePriority::new(child_priority, child_key));
+                    }
+                    continue;
+                }
+                // Trade a low priority merge for a higher priority split, unless the merge would remove the split node
+                (Some(merge), Some(split))
+                    if merge.priority < split.priority
+                        && self.get_node_unchecked(split.node_key).get_parent() != Some(merge.node_key) =>
+                {
+                    Some(merge)
+                }
+                _ => None,
+            };
+
+            let Some(merge) = merge else {
+                break;
+            };
+            merge_queue.pop();
+            merged_nodes.insert(merge.node_key);
+            self.shrink_event(&mut events, merge.node_key);
+            leaf_count -= added_leaves;
+            if let Some(parent_key) = self.get_node_unchecked(merge.node_key).get_parent() {
+                let parent = self.get_node_unchecked(parent_key);
+                let parent_mergeable = parent.children().is_some_and(|children| {
+                    children.iter().all(|child_key| !self.get_node_unchecked(*child_key).has_children())
+                });
+                if parent_mergeable {
+                    merge_queue.push(Reverse(NodePriority::new(priority(parent), parent_key)));
+                }
             }
         }
         events
@@ -56,76 +325,217 @@ where
         None
     }
 
-    fn create_children(&mut self, parent_key: NodeKey) -> Vec<NodeKey> {
-        let (parent_size, parent_pos) = {
-            let parent = self.get_node_unchecked(parent_key);
-            (parent.size(), parent.pos())
-        };
+    /// Returns all leaves overlapping the box. If `include_covered` is set, nodes that lie completely
+    /// inside the box are returned instead of their leaves.
+    fn query_aabb(&self, min: [f32; D], max: [f32; D], include_covered: bool) -> Vec<NodeKey> {
+        self.query(|node| node.overlap_aabb(min, max), include_covered)
+    }
+
+    /// Returns all leaves overlapping the sphere. If `include_covered` is set, nodes that lie completely
+    /// inside the sphere are returned instead of their leaves.
+    fn query_sphere(&self, center: [f32; D], radius: f32, include_covered: bool) -> Vec<NodeKey> {
+        self.query(|node| node.overlap_sphere(center, radius), include_covered)
+    }
+
+    fn query(
+        &self,
+        overlap: impl Fn(&Self::NodeType) -> Overlap,
+        include_covered: bool,
+    ) -> Vec<NodeKey> {
+        let mut found = vec![];
+        let mut pending_node_keys = self.root_items();
+        while let Some(node_key) = pending_node_keys.pop() {
+            let node = self.get_node_unchecked(node_key);
+            match overlap(node) {
+                Overlap::Disjoint => {}
+                Overlap::Contained if include_covered => found.push(node_key),
+                _ => {
+                    if let Some(children) = node.children() {
+                        pending_node_keys.extend(children.iter());
+                    } else {
+                        found.push(node_key);
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    /// Returns the `k` leaves closest to the point, sorted by distance. Leaves containing the point have distance zero.
+    fn nearest_leaves(&self, point: [f32; D], k: usize) -> Vec<(NodeKey, f32)> {
+        self.nearest_leaves_by(k, |node| node.distance_squared(point).sqrt())
+    }
+
+    fn nearest_leaf(&self, point: [f32; D]) -> Option<NodeKey> {
+        self.nearest_leaves(point, 1).first().map(|(node_key, _)| *node_key)
+    }
+
+    /// Best-first search for the `k` closest leaves. `distance` must never be larger for a child than for its parent.
+    fn nearest_leaves_by(
+        &self,
+        k: usize,
+        distance: impl Fn(&Self::NodeType) -> f32,
+    ) -> Vec<(NodeKey, f32)> {
+        let mut found = Vec::with_capacity(k);
+        if k == 0 {
+            return found;
+        }
+
+        let mut pending_nodes = BinaryHeap::new();
+        for node_key in self.root_items() {
+            let node_distance = distance(self.get_node_unchecked(node_key));
+            pending_nodes.push(Reverse(NodePriority::new(node_distance, node_key)));
+        }
 
-        let new_size = parent_size / 2.0;
-        let quart_size = parent_size / 4.0;
+        while let Some(Reverse(NodePriority { priority, node_key })) = pending_nodes.pop() {
+            if let Some(children) = self.get_node_unchecked(node_key).children() {
+                for child_key in children {
+                    let child_distance = distance(self.get_node_unchecked(*child_key));
+                    pending_nodes.push(Reverse(NodePriority::new(child_distance, *child_key)));
+                }
+            } else {
+                found.push((node_key, priority));
+                if found.len() == k {
+                    break;
+                }
+            }
+        }
+        found
+    }
 
+    fn create_children(&mut self, parent_key: NodeKey) -> Vec<NodeKey> {
         let mut new_child_indexes = vec![];
         let num_children = 2usize.pow(D as u32);
 
         for child_index in 0..num_children {
-            let pos = child_position::<D>(child_index);
-            let mut child_pos = parent_pos;
-            child_pos.iter_mut().zip(pos.iter()).for_each(|(out, p)| {
-                let v = *out + *p as f32 * quart_size;
-                *out = v;
-            });
-
-            let mut child = Self::NodeType::from_bounds(new_size, child_pos);
+            let mut child = self.child_node(self.get_node_unchecked(parent_key), child_index);
             child.set_parent(parent_key);
             new_child_indexes.push(self.insert_node(child));
         }
         self.get_mut_node_unchecked(parent_key)
             .set_child_keys(new_child_indexes.as_slice());
+        if self.observer_mut().is_some() {
+            let children = self.child_snapshots(parent_key, &new_child_indexes);
+            self.observer_mut().unwrap().on_split(parent_key, &children);
+        }
         new_child_indexes
     }
 
-    fn remove_children_recursively(&mut self, parent_key: NodeKey) -> Vec<NodeKey> {
+    /// Builds, without inserting it, the child node at `child_index` of a parent node.
+    fn child_node(&self, parent: &Self::NodeType, child_index: usize) -> Self::NodeType {
+        let quart_size = parent.size() / 4.0;
+        let pos = child_position::<D>(child_index);
+        let mut child_pos = parent.pos();
+        child_pos.iter_mut().zip(pos.iter()).for_each(|(out, p)| {
+            let v = *out + *p as f32 * quart_size;
+            *out = v;
+        });
+
+        Self::NodeType::from_bounds(parent.size() / 2.0, child_pos)
+    }
+
+    /// Removes all descendants of a node and returns a snapshot of each of them, intermediate nodes included.
+    fn remove_children_recursively(&mut self, parent_key: NodeKey) -> Vec<NodeSnapshot<D, Self::Face>> {
+        if !self.get_node_unchecked(parent_key).has_children() {
+            return vec![];
+        }
         let mut removed_nodes = vec![];
-        let mut pending_node_keys = self.get_mut_node_unchecked(parent_key).take_children();
-        while let Some(node_key) = pending_node_keys.pop() {
+        let child_depth = self.depth(parent_key) + 1;
+        let mut pending_node_keys: Vec<(NodeKey, usize)> = self
+            .get_mut_node_unchecked(parent_key)
+            .take_children()
+            .into_iter()
+            .map(|node_key| (node_key, child_depth))
+            .collect();
+        while let Some((node_key, depth)) = pending_node_keys.pop() {
             if let Some(mut node) = self.remove_node(node_key) {
+                let snapshot = self.snapshot(node_key, &node, depth);
+                removed_nodes.push(snapshot);
                 let children = node.take_children();
-                if !children.is_empty() {
-                    pending_node_keys.extend(children);
-                } else {
-                    removed_nodes.push(node_key);
-                }
+                pending_node_keys.extend(children.into_iter().map(|child_key| (child_key, depth + 1)));
             }
         }
 
+        if self.is_merge_protected(parent_key) {
+            let removed_keys: Vec<NodeKey> = removed_nodes.iter().map(|snapshot| snapshot.node_key).collect();
+            self.pins_mut().unwrap().transfer(&removed_keys, parent_key);
+        }
+        if let Some(observer) = self.observer_mut() {
+            observer.on_merge(parent_key, &removed_nodes);
+        }
         removed_nodes
     }
 
+    /// The face of a node in its snapshots, see `NodeSnapshot::face`.
+    fn face(&self, node: &Self::NodeType) -> Self::Face;
+
+    /// Describes a node for events. Also used for nodes that were just removed from the tree.
+    fn snapshot(&self, node_key: NodeKey, node: &Self::NodeType, depth: usize) -> NodeSnapshot<D, Self::Face> {
+        NodeSnapshot {
+            node_key,
+            pos: node.pos(),
+            size: node.size(),
+            depth,
+            face: self.face(node),
+            leaf: !node.has_children(),
+        }
+    }
+
+    /// Snapshots of the new children of a node, see `TreeEvent::Grown`.
+    fn child_snapshots(&self, parent_key: NodeKey, new_children: &[NodeKey]) -> Vec<NodeSnapshot<D, Self::Face>> {
+        let child_depth = self.depth(parent_key) + 1;
+        new_children
+            .iter()
+            .map(|child_key| self.snapshot(*child_key, self.get_node_unchecked(*child_key), child_depth))
+            .collect()
+    }
+
     fn grow_event(
         &self,
-        events: &mut Vec<TreeEvent>,
+        events: &mut Vec<TreeEvent<D, Self::Face>>,
         pos: [f32; D],
         parent_key: NodeKey,
         new_children: &[NodeKey],
     ) {
+        let new_children = self.child_snapshots(parent_key, new_children);
         for event in events.iter_mut().rev() {
             if let TreeEvent::Grown { parent, children } = event {
                 if self.get_node_unchecked(*parent).contains_point(pos) {
-                    children.retain(|node_key| !self.get_node_unchecked(*node_key).has_children());
-                    children.extend(new_children.iter());
+                    children.retain(|child| !self.get_node_unchecked(child.node_key).has_children());
+                    children.extend(new_children);
                     return;
                 }
             }
         }
         events.push(TreeEvent::Grown {
             parent: parent_key,
-            children: Vec::from_iter(new_children.iter().copied()),
+            children: new_children,
         });
     }
 
-    fn shrink_event(&mut self, events: &mut Vec<TreeEvent>, parent_key: NodeKey) {
-        let removed_nodes = self.remove_children_recursively(parent_key);
+    fn shrink_event(&mut self, events: &mut Vec<TreeEvent<D, Self::Face>>, parent_key: NodeKey) {
+        // Leaves are the common case, and have nothing to remove or fold
+        if !self.get_node_unchecked(parent_key).has_children() {
+            return;
+        }
+        let mut removed_nodes = self.remove_children_recursively(parent_key);
+        // Fold earlier merges of the removed nodes into this one, so the event describes the nodes before the update
+        let mut folded_nodes = vec![];
+        events.retain_mut(|event| match event {
+            TreeEvent::Shrunk { retained, removed }
+                if removed_nodes.iter().any(|snapshot| snapshot.node_key == *retained) =>
+            {
+                folded_nodes.push(*retained);
+                removed_nodes.append(removed);
+                false
+            }
+            _ => true,
+        });
+        for snapshot in removed_nodes.iter_mut() {
+            if folded_nodes.contains(&snapshot.node_key) {
+                snapshot.leaf = false;
+            }
+        }
         if !removed_nodes.is_empty() {
             events.push(TreeEvent::Shrunk {
                 retained: parent_key,
@@ -134,6 +544,110 @@ where
         }
     }
 
+    /// All leaves in a stable order: depth first from the roots in order, children in child index order.
+    /// Unlike iterating `nodes`, the order only depends on the shape of the tree.
+    fn leaf_keys(&self) -> Vec<NodeKey> {
+        let mut leaves = vec![];
+        let mut pending_node_keys = self.root_items();
+        pending_node_keys.reverse();
+        while let Some(node_key) = pending_node_keys.pop() {
+            match self.get_node_unchecked(node_key).children() {
+                Some(children) => pending_node_keys.extend(children.iter().rev()),
+                None => leaves.push(node_key),
+            }
+        }
+        leaves
+    }
+
+    /// The registered observer, `None` if there is none or the tree does not support observers.
+    fn observer_mut(&mut self) -> Option<&mut BoxedObserver<D, Self::Face>> {
+        None
+    }
+
+    /// Pins of the tree, `None` if the tree does not support pinning.
+    fn pins(&self) -> Option<&Pins> {
+        None
+    }
+
+    fn pins_mut(&mut self) -> Option<&mut Pins> {
+        None
+    }
+
+    /// Pins a node so refinement never merges it or its ancestors. Pins are reference counted, and when a pinned node
+    /// is removed by a manual merge its pins move to the node that replaces it.
+    /// Returns false if the node does not exist or the tree does not support pinning.
+    fn pin(&mut self, node_key: NodeKey) -> bool {
+        if self.get_node(node_key).is_none() || self.pins().is_none() {
+            return false;
+        }
+        let path = self.ancestor_path(node_key);
+        self.pins_mut().unwrap().add(&path, 1);
+        true
+    }
+
+    /// Removes one pin from a node. Returns false if the node was not pinned.
+    fn unpin(&mut self, node_key: NodeKey) -> bool {
+        if self.get_node(node_key).is_none() || self.pins().is_none() {
+            return false;
+        }
+        let path = self.ancestor_path(node_key);
+        self.pins_mut().unwrap().remove(&path)
+    }
+
+    /// Pins every leaf overlapping the box and returns them, so they can be unpinned later.
+    fn pin_region(&mut self, min: [f32; D], max: [f32; D]) -> Vec<NodeKey> {
+        let leaves = self.query_aabb(min, max, false);
+        leaves
+            .into_iter()
+            .filter(|node_key| self.pin(*node_key))
+            .collect()
+    }
+
+    /// True if the node or one of its descendants is pinned.
+    fn is_merge_protected(&self, node_key: NodeKey) -> bool {
+        self.pins().is_some_and(|pins| pins.is_protected(node_key))
+    }
+
+    /// The node followed by all of its ancestors.
+    fn ancestor_path(&self, mut node_key: NodeKey) -> Vec<NodeKey> {
+        let mut path = vec![node_key];
+        while let Some(parent_key) = self.get_node_unchecked(node_key).get_parent() {
+            path.push(parent_key);
+            node_key = parent_key;
+        }
+        path
+    }
+
+    /// Number of ancestors of the node.
+    fn depth(&self, mut node_key: NodeKey) -> usize {
+        let mut depth = 0;
+        while let Some(parent_key) = self.get_node_unchecked(node_key).get_parent() {
+            node_key = parent_key;
+            depth += 1;
+        }
+        depth
+    }
+
+    /// True if the resolution limits allow the node to be split.
+    fn can_split(&self, node_key: NodeKey) -> bool {
+        self.can_split_node(self.get_node_unchecked(node_key), self.depth(node_key))
+    }
+
+    /// Same as `can_split`, for a node at a known depth.
+    fn can_split_node(&self, node: &Self::NodeType, depth: usize) -> bool {
+        node.size() > self.min_size_for(node) && self.max_depth().is_none_or(|max_depth| depth < max_depth)
+    }
+
+    /// Smallest size a node in this region may be split down to. Defaults to `min_size`.
+    fn min_size_for(&self, _node: &Self::NodeType) -> f32 {
+        self.min_size()
+    }
+
+    /// Deepest level nodes may be split down to, roots being at depth 0. Defaults to no limit.
+    fn max_depth(&self) -> Option<usize> {
+        None
+    }
+
     fn min_size(&self) -> f32;
     fn root_items(&self) -> Vec<NodeKey>;
 }
@@ -251,7 +765,7 @@ where
     fn update_neighbor_sizes(
         &mut self,
         node_key: NodeKey,
-        visited_nodes: &mut HashMap<NodeKey, NeighborSizeEvent>,
+        visited_nodes: &mut VisitedNodes<D>,
     ) {
         let mut neighbor_sizes = vec![];
         let node_size = self.get_node_unchecked(node_key).size();
@@ -260,13 +774,12 @@ where
             let mut opposite_dir = direction;
             opposite_dir.iter_mut().for_each(|e| *e *= -1);
                                     
-            for neighbour_key in self.get_neighbors(node_key, direction) {                                
+            for neighbour_key in self.get_neighbors(node_key, direction) {
+                visited_nodes.remember_sides(neighbour_key, self.get_node_unchecked(neighbour_key));
                 if self.update_neighbor_size(node_key, neighbour_key, node_size, opposite_dir)
                     == NeighborSizeEvent::ChangedSize
                 {
-                    visited_nodes
-                        .entry(neighbour_key)
-                        .or_insert(NeighborSizeEvent::ChangedSize);
+                    visited_nodes.mark_changed(neighbour_key);
                 }
 
                 let neighbour_size = self.get_node_unchecked(neighbour_key).size();
@@ -289,7 +802,7 @@ where
                 child_node.neighbor_offsets_mut()[offset_index] = *offset;
             }            
         }
-        visited_nodes.insert(node_key, NeighborSizeEvent::New);
+        visited_nodes.mark_updated(node_key);
     }
 
     fn get_neighbor_offsets(&self, node_key: NodeKey, neighbour_key: NodeKey, direction: [i32; D]) -> Vec<f32> {
@@ -314,7 +827,12 @@ where
         subject_size: f32,
         direction: [i32; D],
     ) -> NeighborSizeEvent {
-        let offsets = self.get_neighbor_offsets(neighbour_key, subject_key, direction);
+        // Like in `update_neighbor_sizes`, the offsets are only kept towards a neighbor that is at least as large
+        let offsets = if subject_size < self.get_node_unchecked(neighbour_key).size() {
+            vec![0.0; D - 1]
+        } else {
+            self.get_neighbor_offsets(neighbour_key, subject_key, direction)
+        };
         let neighbour = self.get_mut_node_unchecked(neighbour_key);        
 
         let neighbor_size = neighbour.size();
@@ -344,14 +862,216 @@ where
     fn insert_and_update_neighbors(
         &mut self,
         f: impl Fn(&Self::NodeType) -> bool,
-    ) -> Vec<TreeEvent> {
+    ) -> Vec<TreeEvent<D, Self::Face>> {
         let mut events = self.insert(f);
         self.update_neighbors_from_events(&mut events);
         events
     }
 
-    fn update_neighbors_from_events(&mut self, events: &mut Vec<TreeEvent>) {
-        let mut visited_nodes = HashMap::new();
+    fn refine_and_update_neighbors(
+        &mut self,
+        f: impl FnMut(&Self::NodeType) -> Refine,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        let mut events = self.refine(f);
+        self.update_neighbors_from_events(&mut events);
+        events
+    }
+
+    fn insert_in_region_and_update_neighbors(
+        &mut self,
+        in_region: impl Fn(&Self::NodeType) -> bool,
+        f: impl Fn(&Self::NodeType) -> bool,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        let mut events = self.insert_in_region(in_region, f);
+        self.update_neighbors_from_events(&mut events);
+        events
+    }
+
+    fn insert_in_aabb_and_update_neighbors(
+        &mut self,
+        min: [f32; D],
+        max: [f32; D],
+        f: impl Fn(&Self::NodeType) -> bool,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        let mut events = self.insert_in_aabb(min, max, f);
+        self.update_neighbors_from_events(&mut events);
+        events
+    }
+
+    fn insert_in_sphere_and_update_neighbors(
+        &mut self,
+        center: [f32; D],
+        radius: f32,
+        f: impl Fn(&Self::NodeType) -> bool,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        let mut events = self.insert_in_sphere(center, radius, f);
+        self.update_neighbors_from_events(&mut events);
+        events
+    }
+
+    /// Like `insert_and_update_neighbors`, but returns the change in the leaf set instead of the events.
+    fn insert_and_diff(&mut self, f: impl Fn(&Self::NodeType) -> bool) -> LeafDiff<D, Self::Face> {
+        let events = self.insert_and_update_neighbors(f);
+        LeafDiff::from_events(self, &events)
+    }
+
+    fn refine_with_budget_and_update_neighbors(
+        &mut self,
+        priority: impl Fn(&Self::NodeType) -> f32,
+        max_leaves: usize,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        let mut events = self.refine_with_budget(priority, max_leaves);
+        self.update_neighbors_from_events(&mut events);
+        events
+    }
+
+    fn refine_with_context_and_update_neighbors(
+        &mut self,
+        f: impl FnMut(&RefineContext<Self::NodeType, D>) -> Refine,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        let mut events = self.refine_with_context(f);
+        self.update_neighbors_from_events(&mut events);
+        events
+    }
+
+    fn insert_with_hysteresis_and_update_neighbors(
+        &mut self,
+        f: impl Fn(&Self::NodeType, f32) -> bool,
+        hysteresis: Hysteresis,
+    ) -> Vec<TreeEvent<D, Self::Face>>
+    where
+        Self::NodeType: RefineStateBehaviour,
+    {
+        let mut events = self.insert_with_hysteresis(f, hysteresis);
+        self.update_neighbors_from_events(&mut events);
+        events
+    }
+
+    /// Splits a single leaf and updates the neighbor sizes of all affected leaves.
+    /// Does nothing if the node does not exist, already has children or the resolution limits forbid the split.
+    fn split(&mut self, node_key: NodeKey) -> Vec<TreeEvent<D, Self::Face>> {
+        let mut events = vec![];
+        match self.get_node(node_key) {
+            Some(node) if !node.has_children() && self.can_split(node_key) => {
+                let parent_pos = node.pos();
+                let new_children = self.create_children(node_key);
+                self.grow_event(&mut events, parent_pos, node_key, &new_children);
+                self.update_neighbors_from_events(&mut events);
+            }
+            _ => {}
+        }
+        events
+    }
+
+    /// Collapses all descendants of a node into it and updates the neighbor sizes of all affected leaves.
+    /// Does nothing if the node does not exist or is a leaf.
+    fn merge(&mut self, node_key: NodeKey) -> Vec<TreeEvent<D, Self::Face>> {
+        let mut events = vec![];
+        if self.get_node(node_key).is_some_and(|node| node.has_children()) {
+            self.shrink_event(&mut events, node_key);
+            self.update_neighbors_from_events(&mut events);
+        }
+        events
+    }
+
+    /// Like `insert`, but keeps the tree 2:1 balanced: neighboring leaves never differ by more than one level.
+    /// Extra splits are added where needed and merges that would break the constraint are refused.
+    fn insert_balanced(&mut self, f: impl Fn(&Self::NodeType) -> bool) -> Vec<TreeEvent<D, Self::Face>> {
+        let mut events = vec![];
+        let mut pending_node_keys = self.root_items();
+        while let Some(node_key) = pending_node_keys.pop() {
+            let node = self.get_node_unchecked(node_key);
+            if f(node) {
+                if let Some(children) = node.children() {
+                    pending_node_keys.extend(children.iter());
+                } else if self.can_split(node_key) {
+                    let parent_pos = node.pos();
+                    let new_children = self.create_children(node_key);
+                    self.grow_event(&mut events, parent_pos, node_key, &new_children);
+                    pending_node_keys.extend(new_children.iter());
+                };
+            } else if let Some(children) = node.children() {
+                if self.can_merge_balanced(node_key) && !self.is_merge_protected(node_key) {
+                    self.shrink_event(&mut events, node_key);
+                } else {
+                    pending_node_keys.extend(children.iter());
+                }
+            }
+        }
+
+        let new_leaves = grown_children(&events);
+        self.enforce_balance(&mut events, new_leaves);
+        events
+    }
+
+    fn insert_balanced_and_update_neighbors(
+        &mut self,
+        f: impl Fn(&Self::NodeType) -> bool,
+    ) -> Vec<TreeEvent<D, Self::Face>> {
+        let mut events = self.insert_balanced(f);
+        self.update_neighbors_from_events(&mut events);
+        events
+    }
+
+    /// Splits leaves until the whole tree is 2:1 balanced.
+    fn balance(&mut self) -> Vec<TreeEvent<D, Self::Face>> {
+        let mut events = vec![];
+        let leaves = self.leaf_keys();
+        self.enforce_balance(&mut events, leaves);
+        self.update_neighbors_from_events(&mut events);
+        events
+    }
+
+    /// True if collapsing the node into a leaf keeps every bordering leaf at least half its size.
+    fn can_merge_balanced(&self, node_key: NodeKey) -> bool {
+        let half_size = self.get_node_unchecked(node_key).size() / 2.0;
+        all_neighbor_directions::<D>().all(|direction| {
+            self.get_neighbors(node_key, direction)
+                .iter()
+                .all(|neighbor_key| self.get_node_unchecked(*neighbor_key).size() >= half_size)
+        })
+    }
+
+    /// Splits neighbors of the given leaves that are more than twice their size, and repeats for the new leaves.
+    fn enforce_balance(&mut self, events: &mut Vec<TreeEvent<D, Self::Face>>, mut pending_leaves: Vec<NodeKey>) {
+        while let Some(leaf_key) = pending_leaves.pop() {
+            match self.get_node(leaf_key) {
+                Some(node) if !node.has_children() => {}
+                _ => continue,
+            }
+
+            let max_size = self.get_node_unchecked(leaf_key).size() * 2.0;
+            let mut split_neighbor = false;
+            for direction in all_neighbor_directions::<D>() {
+                for neighbor_key in self.get_neighbors(leaf_key, direction) {
+                    let neighbor = self.get_node_unchecked(neighbor_key);
+                    if neighbor.has_children()
+                        || neighbor.size() <= max_size
+                        || !self.can_split(neighbor_key)
+                    {
+                        continue;
+                    }
+
+                    let parent_pos = neighbor.pos();
+                    let new_children = self.create_children(neighbor_key);
+                    self.grow_event(events, parent_pos, neighbor_key, &new_children);
+                    pending_leaves.extend(new_children);
+                    split_neighbor = true;
+                }
+            }
+
+            // The new neighbors might still be too large
+            if split_neighbor {
+                pending_leaves.push(leaf_key);
+            }
+        }
+    }
+
+    /// Updates the neighbor sizes of the leaves in `Grown` and `Shrunk` events and appends a `NeighborSizesChanged`
+    /// event for every other leaf whose neighbor sizes changed, in the order the leaves were first reached.
+    /// A leaf whose sides end up as they were gets no event.
+    fn update_neighbors_from_events(&mut self, events: &mut Vec<TreeEvent<D, Self::Face>>) {
+        let mut visited_nodes = VisitedNodes::default();
         for event in events.iter() {
             match event {
                 TreeEvent::Grown {
@@ -359,7 +1079,7 @@ where
                     children,
                 } => {
                     for child in children {
-                        self.update_neighbor_sizes(*child, &mut visited_nodes);
+                        self.update_neighbor_sizes(child.node_key, &mut visited_nodes);
                     }
                 }
                 TreeEvent::Shrunk {
@@ -369,14 +1089,64 @@ where
                 _ => {}
             }
         }
-        for (node_key, neighbor_event) in visited_nodes.iter() {
-            if *neighbor_event == NeighborSizeEvent::ChangedSize {
-                events.push(TreeEvent::NeighborSizesChanged(*node_key));
+        for node_key in visited_nodes.changed() {
+            let changes = visited_nodes.neighbor_changes(node_key, self.get_node_unchecked(node_key));
+            if let Some(observer) = self.observer_mut() {
+                for change in changes.iter() {
+                    observer.on_neighbor_changed(node_key, change);
+                }
+            }
+            if !changes.is_empty() {
+                events.push(TreeEvent::NeighborSizesChanged {
+                    node: node_key,
+                    changes,
+                });
             }
         }
     }
 }
 
+/// All children listed in `Grown` events.
+pub fn grown_children<const D: usize, F>(events: &[TreeEvent<D, F>]) -> Vec<NodeKey> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            TreeEvent::Grown { children, .. } => Some(children.iter().map(|child| child.node_key)),
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
+
+/// Outcome of a refinement predicate for one node.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Refine {
+    Split,
+    Keep,
+    Merge,
+}
+
+/// What a refinement predicate knows about the node it decides for.
+#[derive(Debug)]
+pub struct RefineContext<'a, N, const D: usize> {
+    pub node_key: NodeKey,
+    pub node: &'a N,
+    /// 0 for root nodes.
+    pub depth: usize,
+    pub parent_bounds: Option<([f32; D], [f32; D])>,
+    pub is_leaf: bool,
+    /// The node's current neighbor sizes, see `NeighborBehaviour`.
+    pub neighbor_sizes: &'a [f32],
+}
+
+/// Settings for `insert_with_hysteresis`.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Hysteresis {
+    /// Number of consecutive calls a node has to fail its keep test before it merges. 0 and 1 merge right away.
+    pub merge_frames: u32,
+    /// Added to the scale factor passed to the predicate when testing whether a node keeps its children.
+    pub margin: f32,
+}
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum NeighborSizeEvent {
@@ -385,15 +1155,610 @@ pub enum NeighborSizeEvent {
     None,
 }
 
+/// Nodes reached while updating neighbor sizes, see `TreeNeighbourBehaviour::update_neighbor_sizes`.
+/// Remembers the order nodes were first reached in, so events do not depend on hashing, and the sides of
+/// neighbors from before they were changed.
+#[derive(Debug)]
+pub struct VisitedNodes<const D: usize> {
+    events: HashMap<NodeKey, NeighborSizeEvent>,
+    order: Vec<NodeKey>,
+    // Neighbor sizes and offsets of a node before it was first changed
+    original_sides: HashMap<NodeKey, (Vec<f32>, Vec<f32>)>,
+}
+
+impl<const D: usize> Default for VisitedNodes<D> {
+    fn default() -> Self {
+        Self {
+            events: HashMap::new(),
+            order: vec![],
+            original_sides: HashMap::new(),
+        }
+    }
+}
+
+impl<const D: usize> VisitedNodes<D> {
+    pub fn get(&self, node_key: NodeKey) -> Option<NeighborSizeEvent> {
+        self.events.get(&node_key).copied()
+    }
+
+    /// Marks a neighbor whose sizes changed, unless the node was already reached.
+    pub fn mark_changed(&mut self, node_key: NodeKey) {
+        if !self.events.contains_key(&node_key) {
+            self.events.insert(node_key, NeighborSizeEvent::ChangedSize);
+            self.order.push(node_key);
+        }
+    }
+
+    /// Marks a node whose own neighbor sizes were recomputed.
+    pub fn mark_updated(&mut self, node_key: NodeKey) {
+        if self.events.insert(node_key, NeighborSizeEvent::New).is_none() {
+            self.order.push(node_key);
+        }
+    }
+
+    /// Nodes whose sizes changed without being recomputed, in the order they were first reached.
+    pub fn changed(&self) -> impl Iterator<Item = NodeKey> + '_ {
+        self.order
+            .iter()
+            .copied()
+            .filter(|node_key| self.events[node_key] == NeighborSizeEvent::ChangedSize)
+    }
+
+    /// Keeps the neighbor sizes and offsets of a node before they are changed. Only the first call per node is kept.
+    pub fn remember_sides(&mut self, node_key: NodeKey, node: &impl NeighborBehaviour<D>) {
+        self.original_sides
+            .entry(node_key)
+            .or_insert_with(|| (node.neighbor_sizes().to_vec(), node.neighbor_offsets().to_vec()));
+    }
+
+    /// The sides of a node that differ from the remembered ones.
+    pub fn neighbor_changes(&self, node_key: NodeKey, node: &impl NeighborBehaviour<D>) -> Vec<NeighborChange<D>> {
+        let Some((old_sizes, old_offsets)) = self.original_sides.get(&node_key) else {
+            return vec![];
+        };
+        let num_offsets = D - 1;
+        let offset_range = |index: usize| index * num_offsets..(index + 1) * num_offsets;
+        (0..D * 2)
+            .filter(|index| {
+                old_sizes[*index] != node.neighbor_sizes()[*index]
+                    || old_offsets[offset_range(*index)] != node.neighbor_offsets()[offset_range(*index)]
+            })
+            .map(|index| NeighborChange {
+                side: neighbor_dir_from_index(index),
+                old_size: old_sizes[index],
+                new_size: node.neighbor_sizes()[index],
+                offsets: node.neighbor_offsets()[offset_range(index)].to_vec(),
+            })
+            .collect()
+    }
+}
+
+/// One side of a leaf whose neighbor changed. For `PlanetTree` the side is in the leaf's own face.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NeighborChange<const D: usize> {
+    pub side: [i32; D],
+    pub old_size: f32,
+    pub new_size: f32,
+    /// The new neighbor offsets of the side, see `NeighborBehaviour::neighbor_offsets`.
+    pub offsets: Vec<f32>,
+}
+
+/// A node as it was when an event was created, so removed nodes can still be cleaned up.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NodeSnapshot<const D: usize, F = ()> {
+    pub node_key: NodeKey,
+    pub pos: [f32; D],
+    pub size: f32,
+    /// 0 for root nodes.
+    pub depth: usize,
+    /// Which part of the tree the node belongs to, see `TreeBehaviour::Face`.
+    pub face: F,
+    /// True for new leaves in `Grown`, and for removed nodes in `Shrunk` that were leaves before the update.
+    pub leaf: bool,
+}
+
+impl<const D: usize, F> NodeSnapshot<D, F> {
+    pub fn bounds(&self) -> ([f32; D], [f32; D]) {
+        let half_size = self.size / 2.0;
+        (self.pos.map(|v| v - half_size), self.pos.map(|v| v + half_size))
+    }
+}
+
+/// Events come in a deterministic order that only depends on the tree and the calls made: `Grown` and `Shrunk`
+/// in the order the changes were made, followed by `NeighborSizesChanged` in the order the leaves were reached.
 #[derive(Debug, Clone)]
-pub enum TreeEvent {
+pub enum TreeEvent<const D: usize, F = ()> {
+    /// `children` are the new leaves below `parent`, new intermediate nodes are left out.
     Grown {
         parent: NodeKey,
-        children: Vec<NodeKey>,
+        children: Vec<NodeSnapshot<D, F>>,
     },
+    /// `removed` are all nodes that were below `retained`, intermediate nodes included.
     Shrunk {
         retained: NodeKey,
-        removed: Vec<NodeKey>,
+        removed: Vec<NodeSnapshot<D, F>>,
     },
-    NeighborSizesChanged(NodeKey),
+    /// The neighbor sizes or offsets of an existing leaf changed on the listed sides.
+    NeighborSizesChanged {
+        node: NodeKey,
+        changes: Vec<NeighborChange<D>>,
+    },
+}
+
+/// Heap entry ordered by priority, ties broken by key.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct NodePriority {
+    pub priority: f32,
+    pub node_key: NodeKey,
+}
+
+impl NodePriority {
+    pub fn new(priority: f32, node_key: NodeKey) -> Self {
+        Self { priority, node_key }
+    }
+}
+
+impl Eq for NodePriority {}
+
+impl PartialOrd for NodePriority {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NodePriority {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.priority
+            .total_cmp(&other.priority)
+            .then_with(|| self.node_key.cmp(&other.node_key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        planet_tree::{Direction, PlanetTree, PlanetTreeNode},
+        quad_tree::{QuadTree, QuadTreeNode},
+        test_utils::*,
+    };
+
+    #[test]
+    fn manual_split_and_merge_match_full_refine() {
+        let mut rng = fastrand::Rng::with_seed(11);
+        let mut tree = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        for _ in 0..200 {
+            let internal_keys: Vec<_> = tree.nodes.iter().filter(|(_, node)| node.has_children()).collect();
+            if !internal_keys.is_empty() && rng.usize(..4) == 0 {
+                let node_key = internal_keys[rng.usize(..internal_keys.len())].0;
+                tree.merge(node_key);
+            } else {
+                let leaf_keys = tree.leaf_keys();
+                tree.split(leaf_keys[rng.usize(..leaf_keys.len())]);
+            }
+
+            let split_nodes: HashSet<String> =
+                tree.nodes.values().filter(|node| node.has_children()).map(node_id).collect();
+            let mut full = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+            full.insert_and_update_neighbors(|node| split_nodes.contains(&node_id(node)));
+            assert_eq!(leaf_sides(&tree), leaf_sides(&full));
+        }
+    }
+
+    #[test]
+    fn neighbor_changes_match_updated_nodes() {
+        let mut rng = fastrand::Rng::with_seed(5);
+        let mut tree = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        for _ in 0..60 {
+            let sides_before: HashMap<NodeKey, ([f32; 4], [f32; 4])> = tree
+                .iter_leaf_nodes()
+                .map(|(node_key, node)| (node_key, (node.neighbor_sizes, node.neighbor_offsets)))
+                .collect();
+            let point = [rng.f32() * 64.0 - 32.0, rng.f32() * 64.0 - 32.0];
+            let depth = rng.u32(1..6) as f32;
+            let events = tree.insert_and_update_neighbors(|node| {
+                node.contains_point(point) && node.size > 64.0 / 2f32.powf(depth)
+            });
+
+            let mut changed_nodes = HashSet::new();
+            for event in &events {
+                let TreeEvent::NeighborSizesChanged { node, changes } = event else {
+                    continue;
+                };
+                assert!(changed_nodes.insert(*node));
+                let leaf = tree.get_node_unchecked(*node);
+                let (old_sizes, _) = sides_before[node];
+                for change in changes {
+                    let index = neighbor_index(change.side).unwrap();
+                    assert_eq!(change.old_size, old_sizes[index]);
+                    assert_eq!(change.new_size, leaf.neighbor_sizes[index]);
+                    assert_eq!(change.offsets, [leaf.neighbor_offsets[index]]);
+                }
+            }
+
+            // Every leaf that was kept gets an event exactly when its sides changed
+            for (node_key, node) in tree.iter_leaf_nodes() {
+                if let Some(sides) = sides_before.get(&node_key) {
+                    let changed = *sides != (node.neighbor_sizes, node.neighbor_offsets);
+                    assert_eq!(changed, changed_nodes.contains(&node_key));
+                }
+            }
+        }
+    }
+
+    // Error of keeping a node as a leaf, always smaller for children than for their parent
+    fn budget_priority(node: &QuadTreeNode) -> f32 {
+        let distance = (node.pos[0] - 10.0).hypot(node.pos[1] - 6.0);
+        node.size * (1.0 + 0.9 / (1.0 + distance / 8.0))
+    }
+
+    fn priorities(tree: &QuadTree, keys: impl IntoIterator<Item = NodeKey>) -> Vec<f32> {
+        keys.into_iter().map(|node_key| budget_priority(&tree.nodes[node_key])).collect()
+    }
+
+    fn mergeable_nodes(tree: &QuadTree) -> Vec<NodeKey> {
+        tree.nodes
+            .iter()
+            .filter(|(_, node)| {
+                node.children()
+                    .is_some_and(|children| children.iter().all(|child_key| !tree.nodes[*child_key].has_children()))
+            })
+            .map(|(node_key, _)| node_key)
+            .collect()
+    }
+
+    #[test]
+    fn budget_refine_never_exceeds_max_leaves() {
+        let mut rng = fastrand::Rng::with_seed(2);
+        let mut tree = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        for _ in 0..20 {
+            let max_leaves = rng.usize(1..300);
+            let point = [rng.f32() * 64.0 - 32.0, rng.f32() * 64.0 - 32.0];
+            tree.refine_with_budget_and_update_neighbors(
+                |node| node.size / (1.0 + (node.pos[0] - point[0]).hypot(node.pos[1] - point[1])),
+                max_leaves,
+            );
+            assert!(tree.iter_leaf_nodes().count() <= max_leaves);
+        }
+    }
+
+    #[test]
+    fn budget_refine_splits_highest_priority_first() {
+        let mut tree = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        tree.refine_with_budget_and_update_neighbors(budget_priority, 100);
+        assert!(tree.iter_leaf_nodes().count() > 90);
+
+        let split_nodes = tree.nodes.iter().filter(|(_, node)| node.has_children()).map(|(node_key, _)| node_key);
+        let lowest_split = priorities(&tree, split_nodes).into_iter().fold(f32::MAX, f32::min);
+        let highest_leaf = priorities(&tree, tree.leaf_keys()).into_iter().fold(0.0, f32::max);
+        assert!(highest_leaf <= lowest_split);
+    }
+
+    #[test]
+    fn budget_refine_merges_lowest_priority_first() {
+        let mut tree = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        tree.refine_with_budget_and_update_neighbors(budget_priority, 100);
+        let split_before: HashSet<NodeKey> =
+            tree.nodes.iter().filter(|(_, node)| node.has_children()).map(|(node_key, _)| node_key).collect();
+
+        tree.refine_with_budget_and_update_neighbors(budget_priority, 40);
+        assert!(tree.iter_leaf_nodes().count() <= 40);
+        let merged_nodes = split_before
+            .into_iter()
+            .filter(|node_key| tree.nodes.get(*node_key).is_some_and(|node| !node.has_children()));
+        let highest_merged = priorities(&tree, merged_nodes).into_iter().fold(0.0, f32::max);
+        let lowest_mergeable = priorities(&tree, mergeable_nodes(&tree)).into_iter().fold(f32::MAX, f32::min);
+        assert!(highest_merged > 0.0);
+        assert!(highest_merged <= lowest_mergeable);
+    }
+
+    fn grown_or_shrunk(events: &[TreeEvent<2>]) -> usize {
+        events
+            .iter()
+            .filter(|event| matches!(event, TreeEvent::Grown { .. } | TreeEvent::Shrunk { .. }))
+            .count()
+    }
+
+    #[test]
+    fn hysteresis_delays_merges_of_oscillating_predicate() {
+        let hysteresis = Hysteresis {
+            merge_frames: 3,
+            margin: 0.1,
+        };
+        // Nodes of size 32 are split at a threshold of 20 and merge at a threshold of 40
+        let above = |threshold: f32| move |node: &QuadTreeNode, scale: f32| node.size * scale > threshold;
+
+        let mut tree = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        assert_eq!(grown_or_shrunk(&tree.insert_with_hysteresis(above(20.0), hysteresis)), 1);
+        assert_eq!(tree.iter_leaf_nodes().count(), 16);
+
+        for threshold in [40.0, 20.0, 40.0, 40.0, 20.0, 40.0, 40.0, 20.0] {
+            let events = tree.insert_with_hysteresis(above(threshold), hysteresis);
+            assert_eq!(grown_or_shrunk(&events), 0);
+            assert_eq!(tree.iter_leaf_nodes().count(), 16);
+        }
+
+        for _ in 0..2 {
+            assert_eq!(grown_or_shrunk(&tree.insert_with_hysteresis(above(40.0), hysteresis)), 0);
+        }
+        let events = tree.insert_with_hysteresis(above(40.0), hysteresis);
+        assert_eq!(grown_or_shrunk(&events), 4);
+        assert_eq!(tree.iter_leaf_nodes().count(), 4);
+    }
+
+    #[test]
+    fn region_refine_matches_full_refine() {
+        let radius = 6.0;
+        let near_viewer = |viewer: [f32; 2]| {
+            move |node: &QuadTreeNode| node.size > 2.0 && node.overlap_sphere(viewer, radius) != Overlap::Disjoint
+        };
+
+        let mut full = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        let mut region = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        let start = [10.0, 10.0];
+        full.insert_and_update_neighbors(near_viewer(start));
+        region.insert_and_update_neighbors(near_viewer(start));
+
+        let mut old_viewer = start;
+        for viewer in [[12.0, 11.0], [20.0, -4.0], [-30.0, -30.0]] {
+            let full_events = full.insert_and_update_neighbors(near_viewer(viewer));
+            let region_events = region.insert_in_region_and_update_neighbors(
+                |node| {
+                    node.overlap_sphere(old_viewer, radius) != Overlap::Disjoint
+                        || node.overlap_sphere(viewer, radius) != Overlap::Disjoint
+                },
+                near_viewer(viewer),
+            );
+            assert_eq!(as_text(full_events), as_text(region_events));
+            assert_eq!(as_text(full.nodes.iter()), as_text(region.nodes.iter()));
+            old_viewer = viewer;
+        }
+    }
+
+    // Leaves sharing an edge, found from their world bounds so pairs across face seams are included
+    fn unbalanced_leaf_pairs(tree: &PlanetTree) -> usize {
+        let leaves: Vec<_> = tree.iter_leaf_nodes().map(|(_, node)| node).collect();
+        let mut unbalanced = 0;
+        for (i, a) in leaves.iter().enumerate() {
+            let (a_min, a_max) = a.world_bounds();
+            for b in &leaves[i + 1..] {
+                let (b_min, b_max) = b.world_bounds();
+                let overlap: Vec<f32> = (0..3).map(|i| a_max[i].min(b_max[i]) - a_min[i].max(b_min[i])).collect();
+                let shares_edge = overlap.iter().all(|v| *v > -1e-3) && overlap.iter().any(|v| *v > 1e-3);
+                if shares_edge && (a.size() > b.size() * 2.0 || b.size() > a.size() * 2.0) {
+                    unbalanced += 1;
+                }
+            }
+        }
+        unbalanced
+    }
+
+    #[test]
+    fn balanced_across_planet_tree_seams() {
+        // Refines a strip of the top face along its seam with another face, which is only unbalanced across the seam
+        let along_seam = |axis: usize| {
+            move |node: &PlanetTreeNode| {
+                let (_, max) = node.world_bounds();
+                node.direction() == Direction::ZPos && max[axis] >= 32.0 && node.size() > 1.0
+            }
+        };
+
+        let mut unbalanced = PlanetTree::new(1.0, 64.0, [0.0, 0.0, 0.0]);
+        unbalanced.insert(along_seam(1));
+        assert!(unbalanced_leaf_pairs(&unbalanced) > 0);
+
+        let mut tree = PlanetTree::new(1.0, 64.0, [0.0, 0.0, 0.0]);
+        tree.insert_balanced_and_update_neighbors(along_seam(1));
+        assert_eq!(unbalanced_leaf_pairs(&tree), 0);
+
+        // Merges that would break the balance are refused
+        tree.insert_balanced_and_update_neighbors(along_seam(0));
+        assert_eq!(unbalanced_leaf_pairs(&tree), 0);
+    }
+
+    // Overlap of a node with a query box, computed per axis without the `aabb_*` helpers
+    fn box_overlap<const D: usize>(
+        (min, max): ([f32; D], [f32; D]),
+        query_min: [f32; D],
+        query_max: [f32; D],
+    ) -> Overlap {
+        if (0..D).any(|i| max[i] < query_min[i] || query_max[i] < min[i]) {
+            Overlap::Disjoint
+        } else if (0..D).all(|i| query_min[i] <= min[i] && max[i] <= query_max[i]) {
+            Overlap::Contained
+        } else {
+            Overlap::Partial
+        }
+    }
+
+    // Overlap of a node with a sphere, from the closest point and the corners of the node
+    fn sphere_overlap<const D: usize>((min, max): ([f32; D], [f32; D]), center: [f32; D], radius: f32) -> Overlap {
+        let inside = |point: [f32; D]| (0..D).map(|i| (point[i] - center[i]).powi(2)).sum::<f32>() <= radius * radius;
+        let closest = std::array::from_fn(|i| center[i].clamp(min[i], max[i]));
+        let mut corners =
+            (0..1 << D).map(|corner| std::array::from_fn(|i| if corner >> i & 1 == 0 { min[i] } else { max[i] }));
+        if !inside(closest) {
+            Overlap::Disjoint
+        } else if corners.all(inside) {
+            Overlap::Contained
+        } else {
+            Overlap::Partial
+        }
+    }
+
+    // What `query` should return, from a walk over every node. Nodes below a returned covered node are left out.
+    fn brute_force_query<Tree, const D: usize>(
+        tree: &Tree,
+        overlap: impl Fn(&Tree::NodeType) -> Overlap,
+        include_covered: bool,
+    ) -> Vec<NodeKey>
+    where
+        Tree: TreeBehaviour<D>,
+        Tree::NodeType: Boundary<D> + ChildBehaviour<D> + NeighborBehaviour<D> + std::fmt::Debug,
+    {
+        let mut found = vec![];
+        let mut pending_node_keys: Vec<(NodeKey, bool)> =
+            tree.root_items().into_iter().map(|node_key| (node_key, false)).collect();
+        while let Some((node_key, below_covered)) = pending_node_keys.pop() {
+            let node = tree.get_node_unchecked(node_key);
+            let overlap = overlap(node);
+            let covered = include_covered && overlap == Overlap::Contained;
+            if !below_covered && overlap != Overlap::Disjoint && (covered || !node.has_children()) {
+                found.push(node_key);
+            }
+            for child_key in node.children().unwrap_or_default() {
+                pending_node_keys.push((*child_key, below_covered || covered));
+            }
+        }
+        found.sort();
+        found
+    }
+
+    fn random_point<const D: usize>(rng: &mut fastrand::Rng, extent: f32) -> [f32; D] {
+        std::array::from_fn(|_| (rng.f32() * 2.0 - 1.0) * extent)
+    }
+
+    // A random query box and sphere
+    fn random_shapes<const D: usize>(rng: &mut fastrand::Rng, extent: f32) -> ([f32; D], [f32; D], [f32; D], f32) {
+        let (a, b): ([f32; D], [f32; D]) = (random_point(rng, extent), random_point(rng, extent));
+        let min = std::array::from_fn(|i| a[i].min(b[i]));
+        let max = std::array::from_fn(|i| a[i].max(b[i]));
+        (min, max, random_point(rng, extent), rng.f32() * extent * 0.8)
+    }
+
+    fn sorted(mut node_keys: Vec<NodeKey>) -> Vec<NodeKey> {
+        node_keys.sort();
+        node_keys
+    }
+
+    #[test]
+    fn queries_match_brute_force() {
+        let mut rng = fastrand::Rng::with_seed(5);
+        let mut tree = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        let targets: [[f32; 2]; 3] = std::array::from_fn(|_| random_point(&mut rng, 32.0));
+        tree.insert(|node| targets.iter().any(|target| node.distance_squared(*target) < node.size * node.size));
+
+        let mut covered_internal_nodes = 0;
+        for _ in 0..200 {
+            let (min, max, center, radius) = random_shapes(&mut rng, 40.0);
+            for include_covered in [false, true] {
+                let found = sorted(tree.query_aabb(min, max, include_covered));
+                let expected = brute_force_query(&tree, |node| box_overlap(node.bounds(), min, max), include_covered);
+                assert_eq!(found, expected);
+                covered_internal_nodes += found.iter().filter(|node_key| tree.nodes[**node_key].has_children()).count();
+
+                let found = sorted(tree.query_sphere(center, radius, include_covered));
+                let expected =
+                    brute_force_query(&tree, |node| sphere_overlap(node.bounds(), center, radius), include_covered);
+                assert_eq!(found, expected);
+                covered_internal_nodes += found.iter().filter(|node_key| tree.nodes[**node_key].has_children()).count();
+            }
+        }
+        assert!(covered_internal_nodes > 0);
+    }
+
+    #[test]
+    fn world_queries_match_brute_force() {
+        let mut rng = fastrand::Rng::with_seed(6);
+        let mut tree = PlanetTree::new(1.0, 64.0, [0.0, 0.0, 0.0]);
+        tree.insert_in_sphere_world_and_update_neighbors([32.0, 10.0, -5.0], 30.0, |node| node.size() > 4.0);
+
+        let mut covered_internal_nodes = 0;
+        for _ in 0..200 {
+            let (min, max, center, radius) = random_shapes(&mut rng, 50.0);
+            for include_covered in [false, true] {
+                let found = sorted(tree.query_aabb_world(min, max, include_covered));
+                let expected =
+                    brute_force_query(&tree, |node| box_overlap(node.world_bounds(), min, max), include_covered);
+                assert_eq!(found, expected);
+                covered_internal_nodes += found.iter().filter(|node_key| tree.nodes[**node_key].has_children()).count();
+
+                let found = sorted(tree.query_sphere_world(center, radius, include_covered));
+                let expected = brute_force_query(
+                    &tree,
+                    |node| sphere_overlap(node.world_bounds(), center, radius),
+                    include_covered,
+                );
+                assert_eq!(found, expected);
+                covered_internal_nodes += found.iter().filter(|node_key| tree.nodes[**node_key].has_children()).count();
+            }
+        }
+        assert!(covered_internal_nodes > 0);
+    }
+
+    // Distance from a point to the closest point of a node, without the `aabb_*` helpers
+    fn point_distance<const D: usize>((min, max): ([f32; D], [f32; D]), point: [f32; D]) -> f32 {
+        (0..D).map(|i| (point[i] - point[i].clamp(min[i], max[i])).powi(2)).sum::<f32>().sqrt()
+    }
+
+    // Checks a nearest leaf search against the distances of all leaves. Leaves at equal distances come in any order.
+    fn check_nearest(found: &[(NodeKey, f32)], leaf_distances: &HashMap<NodeKey, f32>, k: usize) {
+        let mut expected: Vec<f32> = leaf_distances.values().copied().collect();
+        expected.sort_by(f32::total_cmp);
+        expected.truncate(k);
+        assert_eq!(found.iter().map(|(_, distance)| *distance).collect::<Vec<_>>(), expected);
+
+        let mut seen = HashSet::new();
+        for (node_key, distance) in found {
+            assert!(seen.insert(*node_key));
+            assert_eq!(leaf_distances[node_key], *distance);
+        }
+    }
+
+    #[test]
+    fn nearest_leaves_match_brute_force() {
+        let mut rng = fastrand::Rng::with_seed(7);
+        let mut tree = QuadTree::new(1.0, 64.0, [0.0, 0.0]);
+        let targets: [[f32; 2]; 3] = std::array::from_fn(|_| random_point(&mut rng, 32.0));
+        tree.insert(|node| targets.iter().any(|target| node.distance_squared(*target) < node.size * node.size));
+        let leaf_keys = tree.leaf_keys();
+
+        // Points inside and outside of the root, and corners of leaves, which lie on the borders of several leaves
+        let mut points: Vec<[f32; 2]> = (0..100).map(|_| random_point(&mut rng, 60.0)).collect();
+        points.extend([[32.0, 32.0], [-32.0, 0.0], [0.0, 0.0], [32.0, 100.0]]);
+        for _ in 0..20 {
+            let (min, _) = tree.nodes[leaf_keys[rng.usize(..leaf_keys.len())]].bounds();
+            points.push(min);
+        }
+
+        for point in points {
+            let leaf_distances: HashMap<NodeKey, f32> = leaf_keys
+                .iter()
+                .map(|node_key| (*node_key, point_distance(tree.nodes[*node_key].bounds(), point)))
+                .collect();
+            for k in [0, 1, 5, leaf_keys.len() + 3] {
+                check_nearest(&tree.nearest_leaves(point, k), &leaf_distances, k);
+            }
+            let nearest = tree.nearest_leaf(point).unwrap();
+            assert_eq!(leaf_distances[&nearest], leaf_distances.values().copied().fold(f32::MAX, f32::min));
+        }
+    }
+
+    #[test]
+    fn nearest_leaves_world_match_brute_force() {
+        let mut rng = fastrand::Rng::with_seed(8);
+        let mut tree = PlanetTree::new(1.0, 64.0, [0.0, 0.0, 0.0]);
+        tree.insert_in_sphere_world_and_update_neighbors([32.0, 10.0, -5.0], 30.0, |node| node.size() > 4.0);
+        let leaf_keys = tree.leaf_keys();
+
+        // Points inside and outside of the cube, on its edges and corners, and on the borders between leaves
+        let mut points: Vec<[f32; 3]> = (0..100).map(|_| random_point(&mut rng, 80.0)).collect();
+        points.extend([[0.0, 0.0, 0.0], [32.0, 32.0, 0.0], [32.0, 32.0, 32.0], [32.0, 0.0, 0.0], [100.0, 0.0, 0.0]]);
+        for _ in 0..20 {
+            let (min, _) = tree.nodes[leaf_keys[rng.usize(..leaf_keys.len())]].world_bounds();
+            points.push(min);
+        }
+
+        for point in points {
+            let leaf_distances: HashMap<NodeKey, f32> = leaf_keys
+                .iter()
+                .map(|node_key| (*node_key, point_distance(tree.nodes[*node_key].world_bounds(), point)))
+                .collect();
+            for k in [0, 1, 5, leaf_keys.len() + 3] {
+                check_nearest(&tree.nearest_leaves_world(point, k), &leaf_distances, k);
+            }
+            let nearest = tree.nearest_leaf_world(point).unwrap();
+            assert_eq!(leaf_distances[&nearest], leaf_distances.values().copied().fold(f32::MAX, f32::min));
+        }
+    }
 }